@@ -0,0 +1,122 @@
+//! Throughput of the message hot path for healthy players taking turns to speak in one channel,
+//! against an in-memory SQLite database. Messages are sent straight to the game actor, since
+//! `handlers::new_message` needs a connection to Discord to look up the channel.
+//!
+//! Run with `cargo bench --bench new_message`.
+
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use criterion::{Criterion, Throughput, criterion_group, criterion_main};
+use patient_zero::{
+    announcements::Announcer,
+    channels::{ChannelMultipliers, Proximity},
+    config::GameConfig,
+    game::{Channels, Game, GameHandle, IncomingMessage},
+    notifications::Notifier,
+    outbox::Outbox,
+    participation::Participation,
+    players::PlayerStore,
+    voice::Occupancy,
+};
+use poise::serenity_prelude::Http;
+use sqlx::{SqlitePool, sqlite::SqlitePoolOptions};
+use tokio::runtime::Runtime;
+
+/// messages sent per iteration
+const MESSAGES: u64 = 1000;
+/// players taking turns to speak
+const PLAYERS: u64 = 50;
+const CHANNEL: u64 = 1;
+
+const CONFIG: &str = r#"
+server_id = 1
+infected_role = 2
+cure_threshold = 50
+message_cooldown = 60
+infection_cooldown = 600
+"#;
+
+async fn memory_pool() -> SqlitePool {
+    // every connection to an in-memory database gets its own database
+    let pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+    sqlx::migrate!().run(&pool).await.unwrap();
+    pool
+}
+
+/// Also returns the player store, to tell when the game has caught up
+async fn game() -> (GameHandle, Arc<PlayerStore>) {
+    let pool = memory_pool().await;
+    let config: GameConfig = toml::from_str(CONFIG).unwrap();
+    let players = Arc::new(PlayerStore::load(&pool, 100).await.unwrap());
+    players.spawn_flusher(pool.clone(), Duration::from_secs(5));
+
+    let game = Game {
+        players: players.clone(),
+        channels: Arc::new(Channels::new(1000)),
+        participation: Arc::new(Participation::load(&pool, &config).await.unwrap()),
+        outbox: Outbox::default(),
+        announcer: Announcer::default(),
+        // healthy players never get notified, so nothing is sent
+        notifier: Notifier::spawn(Arc::new(Http::new("")), pool.clone(), config.cure_threshold),
+        voice: Arc::new(Occupancy::default()),
+        pool,
+        config,
+    }
+    .spawn();
+
+    (game, players)
+}
+
+fn new_message(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let (game, players) = rt.block_on(game());
+    let next_message = AtomicU64::new(1);
+
+    let mut group = c.benchmark_group("new_message");
+    group.throughput(Throughput::Elements(MESSAGES));
+    group.bench_function("healthy", |b| {
+        b.to_async(&rt).iter(|| async {
+            let last = (MESSAGES - 1) % PLAYERS + 1;
+            let expected =
+                players.get(last).map_or(0, |p| p.total_messages) + (MESSAGES / PLAYERS) as i64;
+
+            for i in 0..MESSAGES {
+                let message = next_message.fetch_add(1, Ordering::Relaxed);
+                game.message(IncomingMessage {
+                    author: i % PLAYERS + 1,
+                    channel: CHANNEL,
+                    message,
+                    timestamp: message,
+                    multipliers: ChannelMultipliers::default(),
+                    proximity: Proximity {
+                        buffer: CHANNEL,
+                        starter: None,
+                    },
+                    replied_to: None,
+                    mentions: Vec::new(),
+                })
+                .await;
+            }
+
+            // the game handles messages in order, so it's done once the last message's author has
+            // caught up
+            while players.get(last).map_or(0, |p| p.total_messages) < expected {
+                tokio::task::yield_now().await;
+            }
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, new_message);
+criterion_main!(benches);
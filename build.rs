@@ -1,5 +1,36 @@
+#[path = "build/command_functions.rs"]
+mod command_functions;
+
 // generated by `sqlx migrate build-script`
 fn main() {
     // trigger recompilation when a new migration is added
     println!("cargo:rerun-if-changed=migrations");
+
+    write_command_functions();
+}
+
+/// Writes the names of every `#[poise::command]` function in the commands module to
+/// `$OUT_DIR/command_functions.rs`, so `main` can check they have all been registered.
+fn write_command_functions() {
+    let mut files = vec![std::path::PathBuf::from("src/commands.rs")];
+    if let Ok(dir) = std::fs::read_dir("src/commands") {
+        files.extend(dir.filter_map(|e| Some(e.ok()?.path())));
+    }
+
+    let mut names = Vec::new();
+    for file in files {
+        println!("cargo:rerun-if-changed={}", file.display());
+        let Ok(source) = std::fs::read_to_string(&file) else {
+            continue;
+        };
+
+        names.extend(command_functions::command_functions(&source));
+    }
+
+    let out = std::path::Path::new(&std::env::var("OUT_DIR").unwrap()).join("command_functions.rs");
+    std::fs::write(
+        out,
+        format!("pub const COMMAND_FUNCTIONS: &[&str] = &{:?};\n", names),
+    )
+    .unwrap();
 }
@@ -0,0 +1,145 @@
+//! Finds the `#[poise::command]` functions in a source file. Used by build.rs, and kept in its own
+//! file so the tests can include it too.
+
+/// The names of every function in `source` with a `#[poise::command]` attribute, in order
+pub fn command_functions(source: &str) -> Vec<String> {
+    let mut names = Vec::new();
+    let mut rest = source;
+
+    while let Some(start) = rest.find("#[poise::command") {
+        rest = skip_attribute(&rest[start..]);
+
+        // other attributes and comments can come between the attribute and the function
+        loop {
+            rest = rest.trim_start();
+            if rest.starts_with("//") {
+                rest = rest.split_once('\n').map_or("", |(_, r)| r);
+            } else if rest.starts_with("#[") {
+                rest = skip_attribute(rest);
+            } else {
+                break;
+            }
+        }
+
+        if let Some(name) = function_name(rest) {
+            names.push(name.to_string());
+        }
+    }
+
+    names
+}
+
+/// Skips past the attribute at the start of `source`, which may span several lines
+fn skip_attribute(source: &str) -> &str {
+    let mut depth = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+
+    for (i, c) in source.char_indices() {
+        if in_string {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => (),
+            }
+            continue;
+        }
+
+        match c {
+            '"' => in_string = true,
+            '[' | '(' => depth += 1,
+            ']' | ')' => {
+                depth -= 1;
+                if depth == 0 {
+                    return &source[i + 1..];
+                }
+            }
+            _ => (),
+        }
+    }
+
+    ""
+}
+
+/// The name of the function declared at the start of `source`, whatever its visibility
+fn function_name(source: &str) -> Option<&str> {
+    let mut rest = source;
+    if let Some(r) = rest.strip_prefix("pub") {
+        rest = r.trim_start();
+        if rest.starts_with('(') {
+            rest = &rest[rest.find(')')? + 1..];
+        }
+    }
+
+    let rest = rest.trim_start().strip_prefix("async")?.trim_start();
+    let rest = rest.strip_prefix("fn")?.trim_start();
+    let end = rest
+        .find(|c: char| !c.is_alphanumeric() && c != '_')
+        .unwrap_or(rest.len());
+    (end > 0).then(|| &rest[..end])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_commands() {
+        let source = r#"
+            /// Replies with the current latency.
+            #[poise::command(slash_command)]
+            pub async fn ping(ctx: Context<'_>) -> Result<()> {}
+
+            pub async fn not_a_command(ctx: Context<'_>) -> Result<()> {}
+
+            #[poise::command(context_menu_command = "Infect", rename = "infect-user")]
+            pub async fn infect_user(ctx: Context<'_>, user: User) -> Result<()> {}
+        "#;
+
+        assert_eq!(command_functions(source), ["ping", "infect_user"]);
+    }
+
+    #[test]
+    fn attribute_over_several_lines() {
+        let source = r#"
+            #[poise::command(
+                context_menu_command = "Who infected (this) author?",
+                rename = "who-infected",
+                subcommands("a", "b"),
+            )]
+            pub async fn who_infected(ctx: Context<'_>, msg: Message) -> Result<()> {}
+        "#;
+
+        assert_eq!(command_functions(source), ["who_infected"]);
+    }
+
+    #[test]
+    fn comments_and_attributes_before_the_function() {
+        let source = r#"
+            #[poise::command(slash_command)]
+            /// Shows statistics about the spread of the infection so far.
+            // TODO: more stats
+            #[allow(clippy::too_many_arguments)]
+            pub async fn stats<'a>(ctx: Context<'a>) -> Result<()> {}
+        "#;
+
+        assert_eq!(command_functions(source), ["stats"]);
+    }
+
+    #[test]
+    fn any_visibility() {
+        let source = r#"
+            #[poise::command(slash_command)]
+            pub(crate) async fn join(ctx: Context<'_>) -> Result<()> {}
+
+            #[poise::command(slash_command)]
+            pub ( super ) async fn leave(ctx: Context<'_>) -> Result<()> {}
+
+            #[poise::command(slash_command)]
+            async fn private(ctx: Context<'_>) -> Result<()> {}
+        "#;
+
+        assert_eq!(command_functions(source), ["join", "leave", "private"]);
+    }
+}
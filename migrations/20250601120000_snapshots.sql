@@ -0,0 +1,19 @@
+CREATE TABLE snapshots (
+	id INTEGER PRIMARY KEY NOT NULL,
+	taken_at INTEGER NOT NULL DEFAULT (unixepoch()),
+	infected INTEGER NOT NULL,
+	healthy INTEGER NOT NULL,
+	-- players with a counted message in the last week
+	active_players INTEGER NOT NULL
+);
+
+-- messages sent in each channel between the previous snapshot and this one
+CREATE TABLE snapshot_channels (
+	snapshot INTEGER NOT NULL,
+	channel TEXT NOT NULL,
+	messages INTEGER NOT NULL,
+	PRIMARY KEY (snapshot, channel),
+	FOREIGN KEY (snapshot) REFERENCES snapshots (id) ON DELETE CASCADE
+);
+
+CREATE INDEX idx_snapshots_taken_at ON snapshots (taken_at);
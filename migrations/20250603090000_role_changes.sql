@@ -0,0 +1,15 @@
+-- outbox of discord role changes, written in the same transaction as the state change that
+-- caused them so they can be retried if discord is unavailable
+CREATE TABLE role_changes (
+	id INTEGER PRIMARY KEY NOT NULL,
+	member TEXT NOT NULL,
+	role TEXT NOT NULL,
+	action TEXT NOT NULL CHECK(action IN ('add', 'remove')),
+	created_at INTEGER NOT NULL DEFAULT (unixepoch()),
+	attempts INTEGER NOT NULL DEFAULT 0,
+	last_error TEXT,
+	-- null while the change is still pending
+	completed_at INTEGER
+);
+
+CREATE INDEX idx_rc_pending ON role_changes (completed_at);
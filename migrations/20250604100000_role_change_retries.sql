@@ -0,0 +1,7 @@
+-- earliest time the change should be retried, for backoff
+ALTER TABLE role_changes ADD COLUMN next_attempt_at INTEGER NOT NULL DEFAULT 0;
+-- set once the change has failed too many times (or can't succeed) and won't be retried
+ALTER TABLE role_changes ADD COLUMN failed_at INTEGER;
+
+DROP INDEX idx_rc_pending;
+CREATE INDEX idx_rc_pending ON role_changes (completed_at, failed_at, next_attempt_at);
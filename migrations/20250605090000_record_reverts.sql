@@ -0,0 +1,4 @@
+-- set on records written by /undo or /rollback to the id of the record they revert
+ALTER TABLE infection_records ADD COLUMN reverts INTEGER REFERENCES infection_records (id);
+
+CREATE INDEX idx_ir_reverts ON infection_records (reverts);
@@ -0,0 +1,11 @@
+-- which direct messages each player has opted in to
+CREATE TABLE notification_settings (
+	player TEXT PRIMARY KEY NOT NULL,
+	infected BOOL NOT NULL DEFAULT FALSE,
+	exposed BOOL NOT NULL DEFAULT FALSE,
+	nearing_cure BOOL NOT NULL DEFAULT FALSE,
+	cured BOOL NOT NULL DEFAULT FALSE,
+	-- set when discord refuses to deliver a dm, cleared when the player changes their settings
+	dms_closed_at INTEGER,
+	FOREIGN KEY (player) REFERENCES players (id)
+);
@@ -0,0 +1,7 @@
+-- players who have joined or opted out of the game, instead of the default set by the config
+CREATE TABLE participation (
+	player TEXT PRIMARY KEY NOT NULL,
+	status TEXT NOT NULL CHECK(status IN ('joined', 'opted_out')),
+	changed_at INTEGER NOT NULL DEFAULT (unixepoch()),
+	FOREIGN KEY (player) REFERENCES players (id)
+);
@@ -0,0 +1,6 @@
+-- how an infection spread: 'proximity', 'reply', 'mention' or 'mentioned'. null for infections
+-- that didn't spread from another player, and for cures
+ALTER TABLE infection_records ADD COLUMN vector TEXT;
+
+UPDATE infection_records SET vector = 'proximity'
+WHERE event = 'infected' AND reason LIKE 'Infected by proximity to %';
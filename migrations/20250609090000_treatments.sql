@@ -0,0 +1,12 @@
+-- treatments given by doctors with /treat, used for their cooldown and daily uses
+CREATE TABLE treatments (
+	id INTEGER PRIMARY KEY AUTOINCREMENT,
+	doctor TEXT NOT NULL,
+	patient TEXT NOT NULL,
+	treated_at INTEGER NOT NULL,
+	succeeded BOOLEAN NOT NULL,
+	FOREIGN KEY (doctor) REFERENCES players (id),
+	FOREIGN KEY (patient) REFERENCES players (id)
+);
+
+CREATE INDEX treatments_doctor_idx ON treatments (doctor, treated_at);
@@ -0,0 +1,51 @@
+-- sqlite can't change a check constraint, so infection_records is copied into a new table that
+-- allows 'vaccinated' events. renaming it updates its reference to itself
+CREATE TABLE infection_records_new (
+	id INTEGER PRIMARY KEY NOT NULL,
+	event TEXT NOT NULL CHECK(event IN ('infected', 'cured', 'vaccinated')),
+	target TEXT NOT NULL,
+	source TEXT,
+	reason TEXT,
+	recorded_at INTEGER NOT NULL DEFAULT (unixepoch()),
+	target_total_messages INTEGER NOT NULL,
+	target_sanitized_messages INTEGER NOT NULL,
+	-- set on records written by /undo or /rollback to the id of the record they revert
+	reverts INTEGER REFERENCES infection_records_new (id),
+	-- how an infection spread. null for infections that didn't spread from another player, and
+	-- for cures and vaccinations
+	vector TEXT,
+	FOREIGN KEY (target) REFERENCES players (id),
+	FOREIGN KEY (source) REFERENCES players (id)
+);
+
+INSERT INTO infection_records_new
+	(id, event, target, source, reason, recorded_at, target_total_messages,
+		target_sanitized_messages, reverts, vector)
+SELECT id, event, target, source, reason, recorded_at, target_total_messages,
+	target_sanitized_messages, reverts, vector
+FROM infection_records;
+
+DROP TABLE infection_records;
+ALTER TABLE infection_records_new RENAME TO infection_records;
+
+CREATE INDEX idx_ir_target ON infection_records (target);
+CREATE INDEX idx_ir_source ON infection_records (source);
+CREATE INDEX idx_ir_reverts ON infection_records (reverts);
+
+-- each player's latest vaccine. immune_until is null for vaccines that never wear off
+CREATE TABLE vaccinations (
+	player TEXT PRIMARY KEY NOT NULL,
+	vaccinated_at INTEGER NOT NULL,
+	immune_until INTEGER,
+	-- the strain the vaccine protects against, or null for every strain
+	strain TEXT,
+	FOREIGN KEY (player) REFERENCES players (id)
+);
+
+-- the shared pool of doses /vaccinate takes from. it only ever has one row, created with a full
+-- pool the first time it's used
+CREATE TABLE vaccine_doses (
+	id INTEGER PRIMARY KEY NOT NULL CHECK(id = 1),
+	available INTEGER NOT NULL,
+	refilled_at INTEGER NOT NULL
+);
@@ -0,0 +1,3 @@
+-- set on pending changes for members who have deleted their data, so they're deleted instead of
+-- kept once they've been applied or given up on
+ALTER TABLE role_changes ADD COLUMN forget BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,10 @@
+-- progress towards curing since the player was last infected. it used to be worked out from
+-- sanitized_messages, which cure multipliers and treatments inflated
+ALTER TABLE players ADD COLUMN cure_progress INTEGER NOT NULL DEFAULT 0;
+
+UPDATE players SET cure_progress = MAX(0, sanitized_messages - COALESCE((
+	SELECT target_sanitized_messages FROM infection_records
+	WHERE target = players.id AND event = 'infected'
+	ORDER BY recorded_at DESC, id DESC LIMIT 1
+), sanitized_messages))
+WHERE infected;
@@ -0,0 +1,7 @@
+-- cure reactions are saved as treatments too, so their cooldown and daily uses survive restarts.
+-- they always succeed, since they only count towards curing
+ALTER TABLE treatments ADD COLUMN kind TEXT NOT NULL DEFAULT 'command'
+	CHECK(kind IN ('command', 'reaction'));
+
+DROP INDEX treatments_doctor_idx;
+CREATE INDEX treatments_doctor_idx ON treatments (doctor, kind, treated_at);
@@ -0,0 +1,7 @@
+-- older versions of check_cure recorded cures as infections with no source. rewrite them as
+-- cures so nothing has to recognise them by their reason
+UPDATE infection_records SET event = 'cured'
+WHERE event = 'infected' AND source IS NULL AND (
+	reason GLOB 'Was infected for more than *'
+	OR reason GLOB 'Sent * messages while infected'
+);
@@ -0,0 +1,364 @@
+//! Posts infections and cures to an announcement channel, configured by
+//! `[game.announcements]`. During an outbreak anything over the rate limit is grouped into a
+//! single summary at the end of the minute instead of flooding the channel.
+
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicU32, Ordering},
+    },
+    time::Duration,
+};
+
+use poise::serenity_prelude as serenity;
+use serenity::{ChannelId, CreateAllowedMentions, CreateMessage, Http};
+use tokio::sync::mpsc::{self, error::TrySendError};
+
+use crate::{helpers, models::TransmissionVector};
+
+/// How many announcements can be waiting to be posted before new ones are only counted
+const QUEUE_SIZE: usize = 256;
+/// The length of the rate limit window
+const WINDOW: Duration = Duration::from_secs(60);
+
+const DEFAULT_INFECTED_TEMPLATE: &str = "{target} was infected by {vector} {source} in {channel}";
+const DEFAULT_INFECTED_MANUALLY_TEMPLATE: &str = "{target} was infected";
+const DEFAULT_CURED_TEMPLATE: &str = "{target} recovered after {messages} messages";
+
+#[derive(serde::Deserialize, Clone)]
+pub struct AnnouncementConfig {
+    /// The channel to post announcements in
+    pub channel: u64,
+    /// Whether to announce infections (default true)
+    pub infections: Option<bool>,
+    /// Whether to announce cures (default true)
+    pub cures: Option<bool>,
+    /// Whether to announce infections and cures made by moderators (default true)
+    pub manual: Option<bool>,
+    /// Replaces the source of infections with "someone", for mystery games
+    #[serde(default)]
+    pub hide_source: bool,
+    /// Template for infections that spread from another player. `{target}`, `{source}`,
+    /// `{channel}` and `{vector}` (e.g. "replying to") are replaced
+    pub infected_template: Option<String>,
+    /// Template for infections by moderators. `{target}` is replaced
+    pub infected_manually_template: Option<String>,
+    /// Template for cures. `{target}`, `{messages}` (sent while infected) and `{duration}` are
+    /// replaced
+    pub cured_template: Option<String>,
+    /// The number of announcements to post each minute before the rest are summarised
+    /// (default 5)
+    pub rate_limit: Option<u32>,
+}
+
+pub enum Announcement {
+    /// Infected by `source` in `channel`
+    Infected {
+        target: u64,
+        source: u64,
+        vector: TransmissionVector,
+        channel: u64,
+    },
+    InfectedManually {
+        target: u64,
+    },
+    Cured {
+        target: u64,
+        /// sanitized messages sent while infected
+        messages: i64,
+        /// how long they were infected (seconds)
+        duration: i64,
+        manually: bool,
+    },
+}
+
+impl AnnouncementConfig {
+    /// Formats the announcement, or returns `None` if it's turned off
+    fn format(&self, announcement: &Announcement) -> Option<String> {
+        let manual = self.manual.unwrap_or(true);
+
+        let (template, target) = match announcement {
+            Announcement::Infected { target, .. } if self.infections.unwrap_or(true) => (
+                self.infected_template
+                    .as_deref()
+                    .unwrap_or(DEFAULT_INFECTED_TEMPLATE),
+                target,
+            ),
+            Announcement::InfectedManually { target }
+                if self.infections.unwrap_or(true) && manual =>
+            {
+                (
+                    self.infected_manually_template
+                        .as_deref()
+                        .unwrap_or(DEFAULT_INFECTED_MANUALLY_TEMPLATE),
+                    target,
+                )
+            }
+            Announcement::Cured {
+                target, manually, ..
+            } if self.cures.unwrap_or(true) && (manual || !manually) => (
+                self.cured_template
+                    .as_deref()
+                    .unwrap_or(DEFAULT_CURED_TEMPLATE),
+                target,
+            ),
+            _ => return None,
+        };
+
+        let mut text = template.replace("{target}", &format!("<@{}>", target));
+        match announcement {
+            Announcement::Infected {
+                source,
+                vector,
+                channel,
+                ..
+            } => {
+                let source = match self.hide_source {
+                    true => "someone".to_string(),
+                    false => format!("<@{}>", source),
+                };
+                text = text
+                    .replace("{source}", &source)
+                    .replace("{vector}", vector.describe())
+                    .replace("{channel}", &format!("<#{}>", channel));
+            }
+            Announcement::Cured {
+                messages, duration, ..
+            } => {
+                text = text
+                    .replace("{messages}", &messages.to_string())
+                    .replace("{duration}", &helpers::format_duration(*duration as u64));
+            }
+            Announcement::InfectedManually { .. } => (),
+        }
+
+        Some(text)
+    }
+}
+
+/// Queues announcements for the worker. Does nothing if announcements aren't configured.
+#[derive(Clone, Default)]
+pub struct Announcer(Option<Queue>);
+
+#[derive(Clone)]
+struct Queue {
+    tx: mpsc::Sender<Announcement>,
+    config: Arc<AnnouncementConfig>,
+    overflow: Arc<Overflow>,
+}
+
+/// Announcements that didn't fit in the queue, added to the next summary by the worker
+#[derive(Default)]
+struct Overflow {
+    infections: AtomicU32,
+    cures: AtomicU32,
+}
+
+impl Announcer {
+    /// Spawns the worker that posts announcements, if they're configured
+    pub fn spawn(http: Arc<Http>, config: Option<AnnouncementConfig>) -> Self {
+        let Some(config) = config else {
+            return Self(None);
+        };
+
+        let config = Arc::new(config);
+        let overflow = Arc::new(Overflow::default());
+        let (tx, rx) = mpsc::channel(QUEUE_SIZE);
+        tokio::spawn(run(http, config.clone(), rx, overflow.clone()));
+        Self(Some(Queue {
+            tx,
+            config,
+            overflow,
+        }))
+    }
+
+    /// Queues an announcement without waiting for it to be posted
+    pub fn announce(&self, announcement: Announcement) {
+        let Some(queue) = &self.0 else {
+            return;
+        };
+
+        // the game actor shouldn't wait on Discord - a full queue means the summary is
+        // already going to be very behind, so anything else only needs counting
+        if let Err(TrySendError::Full(announcement)) = queue.tx.try_send(announcement)
+            && queue.config.format(&announcement).is_some()
+        {
+            warn!("Announcement queue is full, adding announcement to the summary");
+            let counter = match announcement {
+                Announcement::Cured { .. } => &queue.overflow.cures,
+                _ => &queue.overflow.infections,
+            };
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+async fn run(
+    http: Arc<Http>,
+    config: Arc<AnnouncementConfig>,
+    mut rx: mpsc::Receiver<Announcement>,
+    overflow: Arc<Overflow>,
+) {
+    let channel = ChannelId::new(config.channel);
+    let rate_limit = config.rate_limit.unwrap_or(5);
+
+    let mut window = tokio::time::interval(WINDOW);
+    let mut posted = 0;
+    // announcements over the rate limit this window, summarised when it ends
+    let (mut infections, mut cures) = (0, 0);
+
+    loop {
+        tokio::select! {
+            _ = window.tick() => {
+                infections += overflow.infections.swap(0, Ordering::Relaxed);
+                cures += overflow.cures.swap(0, Ordering::Relaxed);
+                if infections + cures > 0 {
+                    post(&http, channel, summarise(infections, cures)).await;
+                }
+                (posted, infections, cures) = (0, 0, 0);
+            }
+            announcement = rx.recv() => {
+                let Some(announcement) = announcement else {
+                    break;
+                };
+                let Some(text) = config.format(&announcement) else {
+                    continue;
+                };
+
+                if posted < rate_limit {
+                    post(&http, channel, text).await;
+                    posted += 1;
+                } else if matches!(announcement, Announcement::Cured { .. }) {
+                    cures += 1;
+                } else {
+                    infections += 1;
+                }
+            }
+        }
+    }
+}
+
+fn summarise(infections: u32, cures: u32) -> String {
+    let plural = |n, one, many| format!("{} {}", n, if n == 1 { one } else { many });
+
+    let parts: Vec<_> = [
+        (infections > 0).then(|| plural(infections, "new infection", "new infections")),
+        (cures > 0).then(|| plural(cures, "recovery", "recoveries")),
+    ]
+    .into_iter()
+    .flatten()
+    .collect();
+
+    format!("{} in the last minute", parts.join(" and "))
+}
+
+async fn post(http: &Http, channel: ChannelId, text: String) {
+    // players are mentioned so they're clickable, but shouldn't be pinged
+    let message = CreateMessage::new()
+        .content(text)
+        .allowed_mentions(CreateAllowedMentions::new());
+
+    if let Err(e) = channel.send_message(http, message).await {
+        warn!("Failed to post announcement: {:?}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(toml: &str) -> AnnouncementConfig {
+        toml::from_str(&format!("channel = 1\n{}", toml)).unwrap()
+    }
+
+    fn infected() -> Announcement {
+        Announcement::Infected {
+            target: 1,
+            source: 2,
+            vector: TransmissionVector::Reply,
+            channel: 3,
+        }
+    }
+
+    fn cured(manually: bool) -> Announcement {
+        Announcement::Cured {
+            target: 1,
+            messages: 50,
+            duration: 3723,
+            manually,
+        }
+    }
+
+    #[test]
+    fn default_templates() {
+        let config = config("");
+
+        assert_eq!(
+            config.format(&infected()).unwrap(),
+            format!(
+                "<@1> was infected by {} <@2> in <#3>",
+                TransmissionVector::Reply.describe()
+            )
+        );
+        assert_eq!(
+            config
+                .format(&Announcement::InfectedManually { target: 1 })
+                .unwrap(),
+            "<@1> was infected"
+        );
+        assert_eq!(
+            config.format(&cured(false)).unwrap(),
+            "<@1> recovered after 50 messages"
+        );
+    }
+
+    #[test]
+    fn custom_templates() {
+        let config = config(
+            r#"
+            infected_template = "{source} gave it to {target}"
+            cured_template = "{target} is better after {duration} and {messages} messages"
+            "#,
+        );
+
+        assert_eq!(config.format(&infected()).unwrap(), "<@2> gave it to <@1>");
+        assert_eq!(
+            config.format(&cured(false)).unwrap(),
+            "<@1> is better after 1h 2m 3s and 50 messages"
+        );
+    }
+
+    #[test]
+    fn hides_source() {
+        let config = config(
+            r#"
+            hide_source = true
+            infected_template = "{source} gave it to {target}"
+            "#,
+        );
+
+        assert_eq!(
+            config.format(&infected()).unwrap(),
+            "someone gave it to <@1>"
+        );
+    }
+
+    #[test]
+    fn skips_disabled_announcements() {
+        let config = config(
+            r#"
+            infections = false
+            manual = false
+            "#,
+        );
+
+        assert!(config.format(&infected()).is_none());
+        assert!(
+            config
+                .format(&Announcement::InfectedManually { target: 1 })
+                .is_none()
+        );
+        assert!(config.format(&cured(true)).is_none());
+        assert!(config.format(&cured(false)).is_some());
+    }
+}
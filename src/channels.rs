@@ -0,0 +1,326 @@
+//! Which channels the game runs in, how much each one speeds up spreading and curing, and how
+//! threads share proximity with their channel, configured by `[game.channels]`. Rules can be set
+//! on a channel or a whole category, and threads follow their parent channel.
+
+use std::collections::HashMap;
+
+use poise::serenity_prelude as serenity;
+use serenity::{Channel, ChannelId, Http};
+
+use crate::helpers::BoundedMap;
+
+#[derive(serde::Deserialize, Clone, Default)]
+pub struct ChannelConfig {
+    /// Channels or categories the game runs in. The game runs everywhere if unset
+    pub allow: Option<Vec<u64>>,
+    /// Channels or categories the game ignores, even if they're allowed
+    #[serde(default)]
+    pub deny: Vec<u64>,
+    /// Multipliers for channels or categories, keyed by id
+    #[serde(default)]
+    pub multipliers: HashMap<String, ChannelMultipliers>,
+    /// How threads and forum posts share proximity with their parent channel
+    #[serde(default)]
+    pub threads: ThreadProximity,
+}
+
+#[derive(serde::Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ThreadProximity {
+    /// Threads have their own recent messages, separate from their channel
+    #[default]
+    Separate,
+    /// Threads share recent messages with their parent channel
+    Shared,
+    /// Like `separate`, but the first reply in a thread is next to the message that started it
+    Starter,
+}
+
+#[derive(serde::Deserialize, Clone, Copy, Debug)]
+pub struct ChannelMultipliers {
+    /// How much faster infected players can spread in the channel, by dividing their cooldown
+    /// (default 1). 0 stops spreading entirely
+    #[serde(default = "one")]
+    pub transmission: f64,
+    /// How much each message in the channel counts towards curing (default 1)
+    #[serde(default = "one")]
+    pub cure: f64,
+}
+
+fn one() -> f64 {
+    1.0
+}
+
+impl Default for ChannelMultipliers {
+    fn default() -> Self {
+        Self {
+            transmission: 1.0,
+            cure: 1.0,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ChannelInfo {
+    /// the channel followed by its parents, e.g. a thread, its channel and then the channel's
+    /// category
+    pub ancestry: Vec<u64>,
+    /// who created the channel, if it's a thread or forum post
+    pub thread_owner: Option<u64>,
+    /// set if looking the channel up failed, so `ancestry` may be missing parents
+    pub unresolved: bool,
+}
+
+impl ChannelInfo {
+    /// The parent channel and owner, if the channel is a thread
+    fn thread(&self) -> Option<(u64, u64)> {
+        Some((*self.ancestry.get(1)?, self.thread_owner?))
+    }
+}
+
+/// Where a message looks for the message before it when checking for infections
+#[derive(Clone, Copy, Debug)]
+pub struct Proximity {
+    /// the channel whose recent messages the message is added to
+    pub buffer: u64,
+    /// set for messages in threads that should be next to the thread's starter if they're the
+    /// first reply
+    pub starter: Option<Starter>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Starter {
+    /// the channel the thread was started in
+    pub parent: u64,
+    /// the message the thread was started from, which has the same id as the thread
+    pub message: u64,
+    /// who created the thread, used if the message isn't in `parent`'s recent messages
+    pub owner: u64,
+}
+
+pub struct ChannelRules {
+    config: ChannelConfig,
+    multipliers: HashMap<u64, ChannelMultipliers>,
+    /// only fetched once per channel
+    info: BoundedMap<u64, Option<ChannelInfo>>,
+}
+
+impl ChannelRules {
+    pub fn new(config: ChannelConfig, capacity: usize) -> Self {
+        let multipliers = config
+            .multipliers
+            .iter()
+            .filter_map(|(id, m)| match id.parse() {
+                Ok(id) => Some((id, *m)),
+                Err(_) => {
+                    warn!("Ignoring multipliers for invalid channel id {}", id);
+                    None
+                }
+            })
+            .collect();
+
+        Self {
+            config,
+            multipliers,
+            info: BoundedMap::new(capacity),
+        }
+    }
+
+    /// Whether any rules are configured, so there's any point in looking channels up
+    fn is_empty(&self) -> bool {
+        self.config.allow.is_none()
+            && self.config.deny.is_empty()
+            && self.multipliers.is_empty()
+            && self.config.threads == ThreadProximity::Separate
+    }
+
+    /// Looks up the channel's parents, and whether it's a thread
+    pub async fn lookup(&self, http: &Http, channel: ChannelId) -> ChannelInfo {
+        let mut info = ChannelInfo {
+            ancestry: vec![channel.get()],
+            thread_owner: None,
+            unresolved: false,
+        };
+        if self.is_empty() {
+            return info;
+        }
+
+        let slot = self.info.get_or_insert(&channel.get());
+        let mut slot = slot.lock().await;
+        if let Some(info) = &*slot {
+            return info.clone();
+        }
+
+        let mut next = Some(channel);
+        // a thread's channel's category is as deep as it goes
+        while let Some(id) = next.take()
+            && info.ancestry.len() <= 3
+        {
+            match id.to_channel(http).await {
+                Ok(Channel::Guild(c)) => {
+                    if id == channel && c.thread_metadata.is_some() {
+                        info.thread_owner = c.owner_id.map(|o| o.get());
+                    }
+                    if let Some(parent) = c.parent_id {
+                        info.ancestry.push(parent.get());
+                        next = Some(parent);
+                    }
+                }
+                Ok(_) => (),
+                Err(e) => {
+                    // not cached, so it's tried again on the next message
+                    warn!("Failed to look up channel {}: {:?}", id, e);
+                    info.unresolved = true;
+                    return info;
+                }
+            }
+        }
+
+        *slot = Some(info.clone());
+        info
+    }
+
+    /// Where a message in the channel looks for the message before it
+    pub fn proximity(&self, info: &ChannelInfo) -> Proximity {
+        let channel = info.ancestry[0];
+
+        match (self.config.threads, info.thread()) {
+            (ThreadProximity::Shared, Some((parent, _))) => Proximity {
+                buffer: parent,
+                starter: None,
+            },
+            (ThreadProximity::Starter, Some((parent, owner))) => Proximity {
+                buffer: channel,
+                starter: Some(Starter {
+                    parent,
+                    message: channel,
+                    owner,
+                }),
+            },
+            _ => Proximity {
+                buffer: channel,
+                starter: None,
+            },
+        }
+    }
+
+    /// Whether the game runs in a channel. Channels that couldn't be looked up aren't allowed if
+    /// there are any allow or deny rules, since one of their missing parents might be denied
+    pub fn allows(&self, info: &ChannelInfo) -> bool {
+        if info.unresolved && (self.config.allow.is_some() || !self.config.deny.is_empty()) {
+            return false;
+        }
+
+        let ancestry = &info.ancestry;
+        if ancestry.iter().any(|c| self.config.deny.contains(c)) {
+            return false;
+        }
+
+        match &self.config.allow {
+            Some(allow) => ancestry.iter().any(|c| allow.contains(c)),
+            None => true,
+        }
+    }
+
+    /// The multipliers of the closest channel in the ancestry that has any
+    pub fn multipliers(&self, info: &ChannelInfo) -> ChannelMultipliers {
+        info.ancestry
+            .iter()
+            .find_map(|c| self.multipliers.get(c))
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(toml: &str) -> ChannelRules {
+        ChannelRules::new(toml::from_str(toml).unwrap(), 16)
+    }
+
+    /// a thread (1) in a channel (2) in a category (3)
+    fn thread() -> ChannelInfo {
+        ChannelInfo {
+            ancestry: vec![1, 2, 3],
+            thread_owner: Some(4),
+            unresolved: false,
+        }
+    }
+
+    fn channel(ancestry: &[u64]) -> ChannelInfo {
+        ChannelInfo {
+            ancestry: ancestry.to_vec(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn allows_everywhere_by_default() {
+        let rules = rules("");
+        assert!(rules.allows(&thread()));
+        assert!(rules.allows(&channel(&[5])));
+    }
+
+    #[test]
+    fn allows_by_ancestry() {
+        let rules = rules("allow = [3]\ndeny = [2]");
+        // the channel is denied, even though its category is allowed
+        assert!(!rules.allows(&thread()));
+        assert!(rules.allows(&channel(&[6, 3])));
+        assert!(!rules.allows(&channel(&[5])));
+    }
+
+    #[test]
+    fn denies_unresolved_channels_with_rules() {
+        let unresolved = ChannelInfo {
+            unresolved: true,
+            ..channel(&[6, 3])
+        };
+        assert!(!rules("allow = [3]").allows(&unresolved));
+        assert!(!rules("deny = [2]").allows(&unresolved));
+        assert!(rules("threads = \"shared\"").allows(&unresolved));
+    }
+
+    #[test]
+    fn multipliers_from_closest_channel() {
+        let rules = rules(
+            r#"
+            [multipliers]
+            2 = { cure = 2.0 }
+            3 = { transmission = 3.0 }
+            "#,
+        );
+
+        let m = rules.multipliers(&thread());
+        assert_eq!((m.transmission, m.cure), (1.0, 2.0));
+        let m = rules.multipliers(&channel(&[6, 3]));
+        assert_eq!((m.transmission, m.cure), (3.0, 1.0));
+        let m = rules.multipliers(&channel(&[5]));
+        assert_eq!((m.transmission, m.cure), (1.0, 1.0));
+    }
+
+    #[test]
+    fn proximity_of_threads() {
+        let separate = rules("").proximity(&thread());
+        assert_eq!(separate.buffer, 1);
+        assert!(separate.starter.is_none());
+
+        let shared = rules("threads = \"shared\"").proximity(&thread());
+        assert_eq!(shared.buffer, 2);
+        assert!(shared.starter.is_none());
+
+        let starter = rules("threads = \"starter\"").proximity(&thread());
+        assert_eq!(starter.buffer, 1);
+        let starter = starter.starter.unwrap();
+        assert_eq!((starter.parent, starter.message, starter.owner), (2, 1, 4));
+    }
+
+    #[test]
+    fn proximity_of_channels() {
+        let proximity = rules("threads = \"shared\"").proximity(&channel(&[6, 3]));
+        assert_eq!(proximity.buffer, 6);
+        assert!(proximity.starter.is_none());
+    }
+}
@@ -1,16 +1,28 @@
-use std::time::Duration;
+use std::{collections::HashMap, time::Duration};
 
 use color_eyre::Result;
 use poise::CreateReply;
-use serenity::all::Member;
+use rand::seq::SliceRandom;
+use serenity::all::{
+    ButtonStyle, ChannelId, ComponentInteraction, ComponentInteractionCollector, CreateActionRow,
+    CreateAttachment, CreateButton, CreateInteractionResponse, CreateInteractionResponseMessage,
+    EditInteractionResponse, GuildId, Member, Message, RoleId, User, UserId,
+};
 
 use crate::{
     helpers,
-    models::{InfectionEvent, InfectionRecord},
+    models::InfectionRecord,
+    notifications::NotificationSettings,
+    outbox::FailedRoleChange,
+    participation::Status,
+    privacy, rebuild,
+    revert::{self, Reversal},
+    snapshots::Snapshot,
+    stats::{self, EpidemicStats},
 };
 
 /// Replies with the current latency and uptime of Patient Zero.
-#[poise::command(slash_command, required_permissions = "MANAGE_MESSAGES")]
+#[poise::command(slash_command, default_member_permissions = "MANAGE_MESSAGES")]
 pub async fn ping(
     ctx: crate::Context<'_>,
     #[description = "Whether to show uptime"] detailed: Option<bool>,
@@ -24,8 +36,10 @@ pub async fn ping(
 
     let details = match detailed {
         true => format!(
-            " Process started at <t:{0}:f>, <t:{0}:R>.",
-            ctx.data().started_at
+            " Process started at <t:{0}:f>, <t:{0}:R>. Tracking {1} channels ({2} evicted).",
+            ctx.data().started_at,
+            ctx.data().channels.len(),
+            ctx.data().channels.evictions(),
         ),
         false => "".to_string(),
     };
@@ -41,75 +55,1058 @@ pub async fn ping(
     Ok(())
 }
 
-#[poise::command(slash_command, required_permissions = "MANAGE_MESSAGES")]
+/// Infects a player.
+#[poise::command(slash_command, default_member_permissions = "MANAGE_MESSAGES")]
 pub async fn infect(ctx: crate::Context<'_>, target: Member) -> Result<()> {
+    set_infected(ctx, target.user.id, true).await
+}
+
+/// Cures a player.
+#[poise::command(slash_command, default_member_permissions = "MANAGE_MESSAGES")]
+pub async fn cure(ctx: crate::Context<'_>, target: Member) -> Result<()> {
+    set_infected(ctx, target.user.id, false).await
+}
+
+#[poise::command(
+    context_menu_command = "Infect",
+    rename = "infect-user",
+    default_member_permissions = "MANAGE_MESSAGES"
+)]
+pub async fn infect_user(ctx: crate::Context<'_>, user: User) -> Result<()> {
+    set_infected(ctx, user.id, true).await
+}
+
+#[poise::command(
+    context_menu_command = "Cure",
+    rename = "cure-user",
+    default_member_permissions = "MANAGE_MESSAGES"
+)]
+pub async fn cure_user(ctx: crate::Context<'_>, user: User) -> Result<()> {
+    set_infected(ctx, user.id, false).await
+}
+
+async fn set_infected(ctx: crate::Context<'_>, target: UserId, infected: bool) -> Result<()> {
     let data = ctx.data();
+    let game = &data.game;
 
-    // set last action to now so we don't have a chain reaction of infections
-    let player_id = target.user.id.get().to_string();
-    let player = sqlx::query!(
-        r#"
-        INSERT INTO players (id, infected) VALUES (?, true)
-        ON CONFLICT (id) DO UPDATE SET infected = true, last_action = unixepoch()
-        RETURNING total_messages, sanitized_messages
-        "#,
-        player_id,
+    let roles = match infected {
+        true => member_roles(ctx, target).await?,
+        false => Vec::new(),
+    };
+    let refusal = if !infected {
+        None
+    } else if data.participation.opted_out(target.get()) {
+        Some("has opted out of the game")
+    } else if !data.participation.plays(target.get(), &roles) {
+        Some("isn't playing the game")
+    } else {
+        None
+    };
+    if let Some(refusal) = refusal {
+        ctx.send(
+            CreateReply::default()
+                .content(format!("<@{}> {}.", target, refusal))
+                .ephemeral(true),
+        )
+        .await?;
+        return Ok(());
+    }
+
+    match infected {
+        true => {
+            game.infect(target.get(), roles, ctx.author().id.get())
+                .await?
+        }
+        false => game.cure(target.get(), ctx.author().id.get()).await?,
+    }
+
+    ctx.send(
+        CreateReply::default()
+            .content(format!(
+                "{} <@{}>.",
+                if infected { "Infected" } else { "Cured" },
+                target
+            ))
+            .ephemeral(true),
     )
-    .fetch_one(&data.db_pool)
     .await?;
 
-    let author_id = ctx.author().id.get().to_string();
-    InfectionRecord {
-        event: InfectionEvent::Infected,
-        target: player_id,
-        source: Some(author_id.clone()),
-        reason: Some(format!("Manually infected by <@{}>", author_id)),
-        recorded_at: helpers::now() as i64,
-        target_total_messages: player.total_messages,
-        target_sanitized_messages: player.sanitized_messages,
-    }
-    .save(&data.db_pool)
-    .await?;
+    Ok(())
+}
 
-    target
-        .add_role(ctx.http(), data.game_config.infected_role)
+#[poise::command(
+    context_menu_command = "Show status",
+    rename = "show-status",
+    default_member_permissions = "MANAGE_MESSAGES"
+)]
+pub async fn user_status(ctx: crate::Context<'_>, user: User) -> Result<()> {
+    let data = ctx.data();
+    let content = match data.players.get(user.id.get()) {
+        None => format!("<@{}> hasn't played yet.", user.id),
+        Some(p) if p.infected => {
+            let progress = p.cure_progress;
+            format!(
+                "<@{}> has been infected since <t:{}:R>, and has sent {}/{} of the messages \
+                needed to be cured. They have sent {} messages in total.",
+                user.id,
+                p.infected_at.unwrap_or_default(),
+                progress.max(0),
+                data.game_config.cure_threshold + 1,
+                p.total_messages,
+            )
+        }
+        Some(p) => format!(
+            "<@{}> is healthy. They have sent {} messages in total.",
+            user.id, p.total_messages
+        ),
+    };
+
+    ctx.send(CreateReply::default().content(content).ephemeral(true))
         .await?;
 
     Ok(())
 }
 
-#[poise::command(slash_command, required_permissions = "MANAGE_MESSAGES")]
-pub async fn cure(ctx: crate::Context<'_>, target: Member) -> Result<()> {
+/// Chooses which direct messages you get about the game. Shows your settings if nothing is set.
+#[poise::command(slash_command, category = "Player")]
+pub async fn notifications(
+    ctx: crate::Context<'_>,
+    #[description = "When you're infected"] infected: Option<bool>,
+    #[description = "When you're exposed but don't catch it"] exposed: Option<bool>,
+    #[description = "When you're close to recovering"] nearing_cure: Option<bool>,
+    #[description = "When you recover"] cured: Option<bool>,
+) -> Result<()> {
+    let pool = &ctx.data().db_pool;
+    let player = ctx.author().id.to_string();
+
+    let mut settings = NotificationSettings::get(&player, pool).await?;
+    let changed = [infected, exposed, nearing_cure, cured]
+        .iter()
+        .any(Option::is_some);
+
+    if changed {
+        settings.infected = infected.unwrap_or(settings.infected);
+        settings.exposed = exposed.unwrap_or(settings.exposed);
+        settings.nearing_cure = nearing_cure.unwrap_or(settings.nearing_cure);
+        settings.cured = cured.unwrap_or(settings.cured);
+        settings.save(&player, pool).await?;
+        settings.dms_closed_at = None;
+    }
+
+    let on_off = |on| if on { "on" } else { "off" };
+    let mut content = format!(
+        "{}\n**Infected:** {}\n**Exposed:** {}\n**Nearing cure:** {}\n**Cured:** {}",
+        if changed {
+            "Saved your notification settings."
+        } else {
+            "Your notification settings:"
+        },
+        on_off(settings.infected),
+        on_off(settings.exposed),
+        on_off(settings.nearing_cure),
+        on_off(settings.cured),
+    );
+    if settings.dms_closed_at.is_some() {
+        content.push_str(
+            "\n\nYour DMs were closed the last time I tried to message you, so notifications \
+            are paused. Open your DMs and run this command again to resume them.",
+        );
+    }
+
+    ctx.send(CreateReply::default().content(content).ephemeral(true))
+        .await?;
+
+    Ok(())
+}
+
+/// Chooses whether you play the game. Opting out cures you and stops you being infected.
+#[poise::command(slash_command, category = "Player")]
+pub async fn participation(
+    ctx: crate::Context<'_>,
+    #[description = "Whether you want to play"] playing: bool,
+) -> Result<()> {
+    let status = match playing {
+        true => Status::Joined,
+        false => Status::OptedOut,
+    };
+    set_participation(ctx, status).await
+}
+
+/// Joins the game.
+#[poise::command(slash_command, category = "Player")]
+pub async fn join(ctx: crate::Context<'_>) -> Result<()> {
+    set_participation(ctx, Status::Joined).await
+}
+
+async fn set_participation(ctx: crate::Context<'_>, status: Status) -> Result<()> {
+    ctx.data()
+        .game
+        .set_participation(ctx.author().id.get(), status)
+        .await?;
+
+    let content = match status {
+        Status::Joined => "You're playing! Your messages now count towards the game.",
+        Status::OptedOut => {
+            "You've opted out. You won't be infected, and your messages won't count towards \
+            the game. Run `/participation playing:True` to play again."
+        }
+    };
+    ctx.send(CreateReply::default().content(content).ephemeral(true))
+        .await?;
+
+    Ok(())
+}
+
+/// Tries to cure an infected player. Only doctors can treat players.
+#[poise::command(slash_command, guild_only, category = "Player")]
+pub async fn treat(
+    ctx: crate::Context<'_>,
+    #[description = "The player to treat"] patient: User,
+) -> Result<()> {
     let data = ctx.data();
+    let config = &data.game_config;
+    let roles = ctx
+        .author_member()
+        .await
+        .map(|m| m.roles.clone())
+        .unwrap_or_default();
+
+    let refusal = if config.treatment.is_none() {
+        Some("Treatment isn't enabled in this game.")
+    } else if !roles.iter().any(|r| config.doctor_roles.contains(&r.get())) {
+        Some("Only doctors can treat players.")
+    } else if !data.participation.plays(ctx.author().id.get(), &roles) {
+        Some("You need to be playing to treat anyone.")
+    } else {
+        None
+    };
+    if let Some(refusal) = refusal {
+        ctx.send(CreateReply::default().content(refusal).ephemeral(true))
+            .await?;
+        return Ok(());
+    }
+
+    let outcome = data
+        .game
+        .treat(
+            ctx.author().id.get(),
+            patient.id.get(),
+            ctx.channel_id().get(),
+        )
+        .await?;
 
-    let player_id = target.user.id.get().to_string();
-    let player = sqlx::query!(
-        r#"
-        INSERT INTO players (id, infected) VALUES (?, false)
-        ON CONFLICT (id) DO UPDATE SET infected = false, last_action = unixepoch()
-        RETURNING total_messages, sanitized_messages
-        "#,
-        player_id,
+    let content = match outcome {
+        Ok(t) => format!(
+            "{}{}{}",
+            match t.cured {
+                true => format!("You treated <@{}>, and they've recovered!", patient.id),
+                false => format!("You treated <@{}>, but it didn't work.", patient.id),
+            },
+            match t.doctor_infected {
+                true => " You caught it from them while treating them.",
+                false => "",
+            },
+            match t.uses_left {
+                Some(uses_left) => format!(" You have {} treatments left today.", uses_left),
+                None => String::new(),
+            }
+        ),
+        Err(e) => e.to_string(),
+    };
+    ctx.send(CreateReply::default().content(content).ephemeral(true))
+        .await?;
+
+    Ok(())
+}
+
+/// Vaccinates a player with a dose from the pool.
+#[poise::command(slash_command, default_member_permissions = "MANAGE_MESSAGES")]
+pub async fn vaccinate(
+    ctx: crate::Context<'_>,
+    #[description = "The player to vaccinate"] user: User,
+) -> Result<()> {
+    let data = ctx.data();
+    if data.game_config.vaccination.is_none() {
+        ctx.send(
+            CreateReply::default()
+                .content("Vaccination isn't enabled in this game.")
+                .ephemeral(true),
+        )
+        .await?;
+        return Ok(());
+    }
+
+    let content = match data
+        .game
+        .vaccinate(user.id.get(), ctx.author().id.get())
+        .await?
+    {
+        Ok(v) => format!(
+            "Vaccinated <@{}>, who is immune {}. {} doses left.",
+            user.id,
+            match v.immune_until {
+                Some(t) => format!("until <t:{}:f>", t),
+                None => "for good".to_string(),
+            },
+            v.doses_left
+        ),
+        Err(e) => e.to_string(),
+    };
+    ctx.send(CreateReply::default().content(content).ephemeral(true))
+        .await?;
+
+    Ok(())
+}
+
+#[poise::command(
+    slash_command,
+    subcommands("mydata_export", "mydata_delete"),
+    category = "Player"
+)]
+pub async fn mydata(_ctx: crate::Context<'_>) -> Result<()> {
+    // discord doesn't allow running a command that has subcommands directly
+    Ok(())
+}
+
+/// Sends you a file with everything stored about you.
+#[poise::command(slash_command, rename = "export", category = "Player")]
+pub async fn mydata_export(ctx: crate::Context<'_>) -> Result<()> {
+    let data = privacy::export(&ctx.data().db_pool, ctx.author().id.get()).await?;
+
+    ctx.send(
+        CreateReply::default()
+            .content("Here's everything stored about you.")
+            .attachment(CreateAttachment::bytes(
+                serde_json::to_vec_pretty(&data)?,
+                "mydata.json",
+            ))
+            .ephemeral(true),
     )
-    .fetch_one(&data.db_pool)
     .await?;
 
-    let author_id = ctx.author().id.get().to_string();
-    InfectionRecord {
-        event: InfectionEvent::Cured,
-        target: player_id,
-        source: Some(author_id.clone()),
-        reason: Some(format!("Manually cured by <@{}>", author_id)),
-        recorded_at: helpers::now() as i64,
-        target_total_messages: player.total_messages,
-        target_sanitized_messages: player.sanitized_messages,
-    }
-    .save(&data.db_pool)
+    Ok(())
+}
+
+/// Deletes everything stored about you. Your infections stay in the stats, anonymously.
+#[poise::command(slash_command, rename = "delete", category = "Player")]
+pub async fn mydata_delete(ctx: crate::Context<'_>) -> Result<()> {
+    let prompt = "This will cure you and delete everything stored about you. Your infections \
+        will stay in the stats, but won't be linked to you any more. This can't be undone.";
+    delete_player_data(ctx, ctx.author().id, prompt.to_string()).await
+}
+
+/// Deletes everything stored about a member, e.g. when they ask for it outside of Discord.
+#[poise::command(slash_command, rename = "delete-data")]
+pub async fn delete_data(ctx: crate::Context<'_>, user: User) -> Result<()> {
+    let prompt = format!(
+        "This will cure <@{}> and delete everything stored about them. Their infections will \
+        stay in the stats, but won't be linked to them any more. This can't be undone.",
+        user.id
+    );
+    delete_player_data(ctx, user.id, prompt).await
+}
+
+async fn delete_player_data(ctx: crate::Context<'_>, user: UserId, prompt: String) -> Result<()> {
+    let Some(interaction) = confirm(ctx, prompt).await? else {
+        return Ok(());
+    };
+
+    ctx.data().game.delete_data(user.get()).await?;
+
+    interaction
+        .edit_response(
+            ctx.http(),
+            EditInteractionResponse::new().content(
+                "Deleted. New messages will be counted again unless the member opts out with \
+                `/participation`.",
+            ),
+        )
+        .await?;
+
+    Ok(())
+}
+
+#[poise::command(
+    context_menu_command = "Who infected this author?",
+    rename = "who-infected",
+    default_member_permissions = "MANAGE_MESSAGES"
+)]
+pub async fn who_infected(ctx: crate::Context<'_>, msg: Message) -> Result<()> {
+    let chain = InfectionRecord::trace(
+        &msg.author.id.to_string(),
+        msg.timestamp.unix_timestamp(),
+        &ctx.data().db_pool,
+    )
+    .await?;
+
+    let content = match chain.first() {
+        None => format!("<@{}> wasn't infected when they sent this.", msg.author.id),
+        Some(r) => describe_infection(r),
+    };
+
+    ctx.send(CreateReply::default().content(content).ephemeral(true))
+        .await?;
+
+    Ok(())
+}
+
+#[poise::command(
+    context_menu_command = "Trace from here",
+    rename = "trace",
+    default_member_permissions = "MANAGE_MESSAGES"
+)]
+pub async fn trace_infection(ctx: crate::Context<'_>, msg: Message) -> Result<()> {
+    let chain = InfectionRecord::trace(
+        &msg.author.id.to_string(),
+        msg.timestamp.unix_timestamp(),
+        &ctx.data().db_pool,
+    )
+    .await?;
+
+    let content = match chain.is_empty() {
+        true => format!("<@{}> wasn't infected when they sent this.", msg.author.id),
+        false => chain
+            .iter()
+            .map(describe_infection)
+            .collect::<Vec<_>>()
+            .join("\n"),
+    };
+
+    ctx.send(CreateReply::default().content(content).ephemeral(true))
+        .await?;
+
+    Ok(())
+}
+
+fn describe_infection(r: &InfectionRecord) -> String {
+    format!(
+        "<@{}> was infected <t:{}:R>: {} (record #{})",
+        r.target,
+        r.recorded_at,
+        r.reason.as_deref().unwrap_or("no reason given"),
+        r.id
+    )
+}
+
+/// Infects a group of players at once, after showing who would be infected.
+#[poise::command(
+    slash_command,
+    rename = "infect-bulk",
+    default_member_permissions = "MANAGE_MESSAGES"
+)]
+pub async fn infect_bulk(
+    ctx: crate::Context<'_>,
+    #[description = "Infect everyone with this role"] role: Option<RoleId>,
+    #[description = "Infect everyone who spoke in this channel recently"] channel: Option<
+        ChannelId,
+    >,
+    #[description = "How far back to look in the channel (minutes, default 10)"]
+    #[min = 1]
+    minutes: Option<u32>,
+    #[description = "Infect this many random active players"]
+    #[min = 1]
+    count: Option<u32>,
+    #[description = "Infect this percentage of random active players"]
+    #[min = 1]
+    #[max = 100]
+    percent: Option<u32>,
+) -> Result<()> {
+    let data = ctx.data();
+    let now = helpers::now() as i64;
+    // every member's roles, if they've been fetched
+    let mut roles = None;
+    // caveats about how the targets were picked, shown with the preview
+    let mut note = String::new();
+
+    let (mut targets, description) = match (role, channel, count, percent) {
+        (Some(role), None, None, None) => {
+            ctx.defer_ephemeral().await?;
+            let members = guild_members(ctx).await?;
+            let targets = members
+                .iter()
+                .filter(|m| !m.user.bot && m.roles.contains(&role))
+                .map(|m| m.user.id.get())
+                .collect();
+            roles = Some(members);
+
+            (targets, format!("everyone with <@&{}>", role))
+        }
+        (None, Some(channel), None, None) => {
+            let minutes = minutes.unwrap_or(10);
+            let since = (now - minutes as i64 * 60) as u64;
+
+            // only the last few messages in each channel are kept, so this is best effort
+            let (targets, seen) = match data.channels.get(&channel.get()) {
+                Some(buf) => {
+                    let buf = buf.lock().await;
+                    let targets = buf
+                        .iter()
+                        .filter(|(.., timestamp)| *timestamp >= since)
+                        .map(|(author, ..)| author)
+                        .collect();
+                    (targets, buf.iter().count())
+                }
+                None => (Vec::new(), 0),
+            };
+            note = format!(
+                "\nThis is based on only the last {} messages seen in <#{}>.",
+                seen, channel
+            );
+
+            (
+                targets,
+                format!(
+                    "everyone who spoke in <#{}> in the last {} minutes",
+                    channel, minutes
+                ),
+            )
+        }
+        (None, None, count, percent) if count.is_some() != percent.is_some() => {
+            let active_since = now - stats::DEFAULT_ACTIVE_WINDOW;
+            let mut active = data
+                .players
+                .ids_where(|p| !p.infected && p.last_action >= active_since);
+            active.shuffle(&mut rand::rng());
+
+            let n = match (count, percent) {
+                (Some(count), _) => count as usize,
+                (_, Some(percent)) => (active.len() * percent as usize).div_ceil(100),
+                _ => unreachable!(),
+            };
+            active.truncate(n);
+
+            let description = match count {
+                Some(count) => format!("{} random active players", count),
+                None => format!("{}% of active players at random", percent.unwrap()),
+            };
+            (active, description)
+        }
+        _ => {
+            ctx.send(
+                CreateReply::default()
+                    .content("Pick exactly one of `role`, `channel`, `count` or `percent`.")
+                    .ephemeral(true),
+            )
+            .await?;
+            return Ok(());
+        }
+    };
+
+    targets.sort_unstable();
+    targets.dedup();
+
+    // in opt-in games, whether someone plays can depend on their roles
+    if roles.is_none() && data.participation.uses_roles() {
+        ctx.defer_ephemeral().await?;
+        roles = Some(guild_members(ctx).await?);
+    }
+    let mut roles: HashMap<_, _> = roles
+        .unwrap_or_default()
+        .into_iter()
+        .map(|m| (m.user.id.get(), m.roles))
+        .collect();
+    let targets: Vec<_> = targets
+        .into_iter()
+        .map(|t| (t, roles.remove(&t).unwrap_or_default()))
+        .filter(|(t, roles)| {
+            !data.players.get(*t).is_some_and(|p| p.infected) && data.participation.plays(*t, roles)
+        })
+        .collect();
+
+    if targets.is_empty() {
+        ctx.send(
+            CreateReply::default()
+                .content(format!(
+                    "Nobody to infect: there are no healthy players in {}.{}",
+                    description, note
+                ))
+                .ephemeral(true),
+        )
+        .await?;
+        return Ok(());
+    }
+
+    let preview = format!(
+        "This will infect {} players ({}): {}{}",
+        targets.len(),
+        description,
+        mention_list(&targets.iter().map(|(t, _)| *t).collect::<Vec<_>>()),
+        note
+    );
+
+    let Some(interaction) = confirm(ctx, preview).await? else {
+        return Ok(());
+    };
+
+    let reason = format!("Bulk infected by <@{}>: {}", ctx.author().id, description);
+    let batch = data
+        .game
+        .infect_batch(targets, reason, ctx.author().id.get())
+        .await?;
+
+    let mut content = format!("Infected {} players.", batch.infected.len());
+    if !batch.failed.is_empty() {
+        content.push_str(&format!(
+            " Failed to infect {} players, see the logs: {}",
+            batch.failed.len(),
+            mention_list(&batch.failed)
+        ));
+    }
+    interaction
+        .edit_response(ctx.http(), EditInteractionResponse::new().content(content))
+        .await?;
+
+    Ok(())
+}
+
+/// Fetches every member of the game's server
+async fn guild_members(ctx: crate::Context<'_>) -> Result<Vec<Member>> {
+    let guild_id = GuildId::new(ctx.data().game_config.server_id);
+
+    let mut members = Vec::new();
+    let mut after = None;
+    loop {
+        let page = guild_id.members(ctx.http(), Some(1000), after).await?;
+        let Some(last) = page.last() else {
+            break;
+        };
+        after = Some(last.user.id);
+        members.extend(page);
+    }
+    Ok(members)
+}
+
+/// The member's roles if [`Participation::plays`] needs them, or none if it doesn't or they've
+/// left the server
+///
+/// [`Participation::plays`]: crate::participation::Participation::plays
+async fn member_roles(ctx: crate::Context<'_>, user: UserId) -> Result<Vec<RoleId>> {
+    let data = ctx.data();
+    if !data.participation.uses_roles() {
+        return Ok(Vec::new());
+    }
+
+    let guild_id = GuildId::new(data.game_config.server_id);
+    Ok(match guild_id.member(ctx.http(), user).await {
+        Ok(member) => member.roles,
+        Err(e) => {
+            debug!("Couldn't fetch member {}: {:?}", user, e);
+            Vec::new()
+        }
+    })
+}
+
+/// Mentions each user, cutting the list short so it fits in a message
+fn mention_list(users: &[u64]) -> String {
+    const MAX_MENTIONS: usize = 50;
+
+    let mut list = users
+        .iter()
+        .take(MAX_MENTIONS)
+        .map(|u| format!("<@{}>", u))
+        .collect::<Vec<_>>()
+        .join(", ");
+    if users.len() > MAX_MENTIONS {
+        list.push_str(&format!(" and {} more", users.len() - MAX_MENTIONS));
+    }
+    list
+}
+
+/// Shows `prompt` with confirm and cancel buttons. Returns the button interaction if the author
+/// confirmed, after acknowledging it - the caller should edit its response with the outcome.
+async fn confirm(ctx: crate::Context<'_>, prompt: String) -> Result<Option<ComponentInteraction>> {
+    // unique to this prompt, so other prompts' buttons aren't picked up
+    let prompt_id = rand::random::<u64>();
+    let confirm_id = format!("{}-confirm", prompt_id);
+    let cancel_id = format!("{}-cancel", prompt_id);
+
+    ctx.send(
+        CreateReply::default()
+            .content(prompt)
+            .ephemeral(true)
+            .components(vec![CreateActionRow::Buttons(vec![
+                CreateButton::new(&confirm_id)
+                    .label("Confirm")
+                    .style(ButtonStyle::Danger),
+                CreateButton::new(&cancel_id).label("Cancel"),
+            ])]),
+    )
     .await?;
 
-    target
-        .remove_role(ctx.http(), data.game_config.infected_role)
+    let ids = [confirm_id.clone(), cancel_id];
+    let interaction = ComponentInteractionCollector::new(ctx.serenity_context())
+        .author_id(ctx.author().id)
+        .filter(move |i| ids.contains(&i.data.custom_id))
+        .timeout(Duration::from_secs(60))
+        .await;
+
+    let Some(interaction) = interaction else {
+        ctx.send(
+            CreateReply::default()
+                .content("Timed out, nothing was changed.")
+                .ephemeral(true),
+        )
+        .await?;
+        return Ok(None);
+    };
+
+    let confirmed = interaction.data.custom_id == confirm_id;
+    interaction
+        .create_response(
+            ctx.http(),
+            CreateInteractionResponse::UpdateMessage(
+                CreateInteractionResponseMessage::new()
+                    .content(match confirmed {
+                        true => "Working on it...",
+                        false => "Cancelled, nothing was changed.",
+                    })
+                    .components(vec![]),
+            ),
+        )
+        .await?;
+
+    Ok(confirmed.then_some(interaction))
+}
+
+/// Reverts a single infection record, after showing what would change.
+#[poise::command(slash_command, default_member_permissions = "MANAGE_MESSAGES")]
+pub async fn undo(
+    ctx: crate::Context<'_>,
+    #[description = "The record to undo, as shown when tracing an infection"]
+    #[min = 1]
+    record: i64,
+) -> Result<()> {
+    let data = ctx.data();
+
+    let reversal = match revert::plan_undo(&data.db_pool, record).await? {
+        Ok(r) => r,
+        Err(e) => {
+            ctx.send(
+                CreateReply::default()
+                    .content(e.to_string())
+                    .ephemeral(true),
+            )
+            .await?;
+            return Ok(());
+        }
+    };
+
+    let preview = format!("This will revert {}.", describe_reversal(&reversal));
+    let Some(interaction) = confirm(ctx, preview).await? else {
+        return Ok(());
+    };
+
+    let content = match data.game.undo(record, ctx.author().id.get()).await? {
+        Ok(r) => format!("Reverted {}.", describe_reversal(&r)),
+        Err(e) => e.to_string(),
+    };
+    interaction
+        .edit_response(ctx.http(), EditInteractionResponse::new().content(content))
         .await?;
 
     Ok(())
 }
+
+/// Reverts every infection record after a point in time, after showing what would change.
+#[poise::command(slash_command, default_member_permissions = "MANAGE_MESSAGES")]
+pub async fn rollback(
+    ctx: crate::Context<'_>,
+    #[description = "Revert everything recorded after this unix timestamp"] timestamp: i64,
+) -> Result<()> {
+    let data = ctx.data();
+
+    let reversals = revert::plan_rollback(&data.db_pool, timestamp).await?;
+    if reversals.is_empty() {
+        ctx.send(
+            CreateReply::default()
+                .content(format!(
+                    "Nothing has been recorded since <t:{}:f>.",
+                    timestamp
+                ))
+                .ephemeral(true),
+        )
+        .await?;
+        return Ok(());
+    }
+
+    let preview = format!(
+        "This will revert everything since <t:{}:f>:\n{}",
+        timestamp,
+        describe_reversals(&reversals)
+    );
+    let Some(interaction) = confirm(ctx, preview).await? else {
+        return Ok(());
+    };
+
+    let reversals = data.game.rollback(timestamp, ctx.author().id.get()).await?;
+    interaction
+        .edit_response(
+            ctx.http(),
+            EditInteractionResponse::new().content(format!(
+                "Reverted everything since <t:{}:f>:\n{}",
+                timestamp,
+                describe_reversals(&reversals)
+            )),
+        )
+        .await?;
+
+    Ok(())
+}
+
+fn describe_reversal(r: &Reversal) -> String {
+    let ids = r
+        .records
+        .iter()
+        .map(|(record, _)| format!("#{}", record.id))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    format!(
+        "{} {} for <@{}>, leaving them {}",
+        if r.records.len() == 1 {
+            "record"
+        } else {
+            "records"
+        },
+        ids,
+        r.target,
+        if r.infected() { "infected" } else { "healthy" }
+    )
+}
+
+/// One line per player, cut short so it fits in a message
+fn describe_reversals(reversals: &[Reversal]) -> String {
+    const MAX_LINES: usize = 15;
+
+    let mut lines: Vec<_> = reversals
+        .iter()
+        .take(MAX_LINES)
+        .map(describe_reversal)
+        .collect();
+    if reversals.len() > MAX_LINES {
+        lines.push(format!(
+            "...and {} more players",
+            reversals.len() - MAX_LINES
+        ));
+    }
+    lines.join("\n")
+}
+
+/// Shows statistics about the spread of the infection so far.
+#[poise::command(slash_command, default_member_permissions = "MANAGE_MESSAGES")]
+pub async fn stats(
+    ctx: crate::Context<'_>,
+    #[description = "Length of the windows used to estimate R (minutes, default 60)"]
+    #[min = 1]
+    window: Option<u32>,
+    #[description = "Players who haven't spoken in this many days are inactive (default 7)"]
+    #[min = 1]
+    active_days: Option<u32>,
+    #[description = "Whether to attach the raw data as CSV"] export: Option<bool>,
+) -> Result<()> {
+    ctx.defer().await?;
+
+    let window = window.map_or(60 * 60, |w| w as i64 * 60);
+    let active_window =
+        active_days.map_or(stats::DEFAULT_ACTIVE_WINDOW, |d| d as i64 * 24 * 60 * 60);
+    let now = helpers::now() as i64;
+    let stats = EpidemicStats::load(
+        &ctx.data().db_pool,
+        window,
+        active_window,
+        ctx.data().game_config.strain.as_deref(),
+        now,
+    )
+    .await?;
+
+    let r = match stats.r_windows.iter().rev().find(|w| w.r.is_some()) {
+        Some(w) => format!("{:.2} (<t:{}:f> to <t:{}:f>)", w.r.unwrap(), w.start, w.end),
+        None => "not enough data".to_string(),
+    };
+    let attack_rate = match stats.attack_rate {
+        Some(a) => format!(
+            "{:.1}% of {} active players",
+            a * 100.0,
+            stats.active_players
+        ),
+        None => "no active players".to_string(),
+    };
+    let mean_duration = match stats.mean_duration {
+        Some(d) => helpers::format_duration(d as u64),
+        None => "nobody has been cured yet".to_string(),
+    };
+    let peak = match stats.peak_prevalence {
+        Some((n, t)) => format!("{} players at <t:{}:f>", n, t),
+        None => "nobody has been infected yet".to_string(),
+    };
+    let coverage = match stats.coverage {
+        Some(c) => format!(
+            "{} players, {:.1}% of active players",
+            stats.vaccinated,
+            c * 100.0
+        ),
+        None => format!("{} players", stats.vaccinated),
+    };
+    let doubling_time = match stats.doubling_time {
+        Some(t) => helpers::format_duration(t as u64),
+        None => "not enough data".to_string(),
+    };
+
+    let mut reply = CreateReply::default().content(format!(
+        "**Infections:** {} total, {} ongoing\n\
+        **Latest R:** {}\n\
+        **Attack rate:** {}\n\
+        **Mean infection duration:** {}\n\
+        **Peak prevalence:** {}\n\
+        **Doubling time:** {}\n\
+        **Protected by a vaccine:** {}",
+        stats.episodes.len(),
+        stats.currently_infected,
+        r,
+        attack_rate,
+        mean_duration,
+        peak,
+        doubling_time,
+        coverage,
+    ));
+
+    if export.unwrap_or(false) {
+        let snapshots = Snapshot::since(&ctx.data().db_pool, 0).await?;
+        reply = reply
+            .attachment(CreateAttachment::bytes(
+                Snapshot::csv(&snapshots),
+                "snapshots.csv",
+            ))
+            .attachment(CreateAttachment::bytes(
+                stats.episodes_csv(),
+                "episodes.csv",
+            ))
+            .attachment(CreateAttachment::bytes(
+                stats.r_windows_csv(),
+                "r_windows.csv",
+            ));
+    }
+
+    ctx.send(reply).await?;
+
+    Ok(())
+}
+
+/// Checks `players` against the infection records, optionally repairing it to match.
+#[poise::command(slash_command, default_member_permissions = "MANAGE_MESSAGES")]
+pub async fn rebuild(
+    ctx: crate::Context<'_>,
+    #[description = "Whether to update players and roles to match the records"] repair: Option<
+        bool,
+    >,
+) -> Result<()> {
+    ctx.defer().await?;
+
+    let data = ctx.data();
+    let repair = repair.unwrap_or(false);
+
+    let discrepancies = data.game.rebuild(repair).await?;
+
+    let report = discrepancies
+        .iter()
+        .map(rebuild::describe)
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    let summary = match (discrepancies.len(), repair) {
+        (0, _) => "The players table matches the infection records.".to_string(),
+        (n, true) => format!("Repaired {} players.", n),
+        (n, false) => format!(
+            "Found {} players that don't match the infection records.",
+            n
+        ),
+    };
+
+    let mut reply = CreateReply::default().content(summary);
+    if !discrepancies.is_empty() {
+        reply = reply.attachment(CreateAttachment::bytes(report, "discrepancies.txt"));
+    }
+    ctx.send(reply).await?;
+
+    Ok(())
+}
+
+/// Lists role changes that couldn't be applied, optionally queueing them to be retried.
+#[poise::command(slash_command, default_member_permissions = "MANAGE_MESSAGES")]
+pub async fn outbox(
+    ctx: crate::Context<'_>,
+    #[description = "Whether to retry every failed role change"] retry: Option<bool>,
+) -> Result<()> {
+    let data = ctx.data();
+
+    let failed = FailedRoleChange::all(&data.db_pool).await?;
+    if failed.is_empty() {
+        ctx.say("There are no failed role changes.").await?;
+        return Ok(());
+    }
+
+    if retry.unwrap_or(false) {
+        let n = FailedRoleChange::retry_all(&data.db_pool).await?;
+        data.outbox.wake();
+        ctx.say(format!("Retrying {} failed role changes.", n))
+            .await?;
+        return Ok(());
+    }
+
+    let report = failed
+        .iter()
+        .map(|f| {
+            format!(
+                "#{} {:?} <@{}> - {} attempts, failed <t:{}:R>: {}",
+                f.id,
+                f.action,
+                f.member,
+                f.attempts,
+                f.failed_at.unwrap_or_default(),
+                f.last_error.as_deref().unwrap_or("unknown error"),
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    ctx.send(
+        CreateReply::default()
+            .content(format!("{} role changes failed permanently.", failed.len()))
+            .attachment(CreateAttachment::bytes(report, "failed_role_changes.txt")),
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Administrative commands for the bot itself.
+#[poise::command(
+    slash_command,
+    subcommands("sync_commands", "delete_data"),
+    default_member_permissions = "MANAGE_MESSAGES"
+)]
+pub async fn admin(_ctx: crate::Context<'_>) -> Result<()> {
+    // discord doesn't allow running a command that has subcommands directly
+    Ok(())
+}
+
+/// Re-registers every command in the game's server.
+#[poise::command(slash_command, rename = "sync-commands")]
+pub async fn sync_commands(ctx: crate::Context<'_>) -> Result<()> {
+    let commands = &ctx.framework().options().commands;
+    poise::builtins::register_in_guild(
+        ctx,
+        commands,
+        GuildId::new(ctx.data().game_config.server_id),
+    )
+    .await?;
+
+    ctx.send(
+        CreateReply::default()
+            .content(format!("Registered {} commands.", commands.len()))
+            .ephemeral(true),
+    )
+    .await?;
+
+    Ok(())
+}
@@ -1,5 +1,12 @@
+use std::collections::HashMap;
+
 use color_eyre::{Result, eyre::WrapErr};
 
+use crate::{
+    announcements::AnnouncementConfig, channels::ChannelConfig, permissions::CommandPermissions,
+    treatments::TreatmentConfig, vaccination::VaccinationConfig, voice::VoiceConfig,
+};
+
 #[derive(serde::Deserialize)]
 pub struct Config {
     pub bot: BotConfig,
@@ -10,9 +17,19 @@ pub struct Config {
 pub struct BotConfig {
     pub token: String,
     pub db_url: String,
+    /// How often message counts are written to the database (seconds, default 5)
+    pub flush_interval: Option<u64>,
+    /// The number of players with unsaved message counts that triggers an early write
+    /// (default 100)
+    pub flush_batch_size: Option<usize>,
+    /// The max number of channels to keep recent messages for (default 1000)
+    pub channel_capacity: Option<usize>,
+    /// How long a channel can go without messages before its recent messages are forgotten
+    /// (seconds, default 1 day)
+    pub channel_idle_timeout: Option<u64>,
 }
 
-#[derive(serde::Deserialize)]
+#[derive(serde::Deserialize, Clone)]
 pub struct GameConfig {
     /// The server to run the game in
     pub server_id: u64,
@@ -22,7 +39,13 @@ pub struct GameConfig {
     pub immune_roles: Option<Vec<u64>>,
     /// Roles that always count as being infected
     pub carrier_roles: Option<Vec<u64>>,
-    /// The number of messages that must be sent while infected to be cured
+    /// Only members with `join_role` or who have run `/join` play. Otherwise everyone plays
+    /// unless they opt out
+    #[serde(default)]
+    pub opt_in: bool,
+    /// The role that lets members play in opt-in games
+    pub join_role: Option<u64>,
+    /// Players are cured once they have sent more than this many messages while infected
     pub cure_threshold: u32,
     /// The max amount of time before a player is cured automatically (seconds)
     pub cure_timeout: Option<u64>,
@@ -30,6 +53,77 @@ pub struct GameConfig {
     pub message_cooldown: u32,
     /// The minimum amount of time between infections from one person
     pub infection_cooldown: u32,
+    /// Ways infections spread besides proximity
+    #[serde(default)]
+    pub vectors: VectorsConfig,
+    /// Reactions that spread infections or help cure them. Reactions are ignored if unset
+    pub reactions: Option<ReactionConfig>,
+    /// Voice channels spreading infections between members in them. Voice channels are ignored
+    /// if unset
+    pub voice: Option<VoiceConfig>,
+    /// Roles that can help cure players, with cure reactions and `/treat`
+    #[serde(default)]
+    pub doctor_roles: Vec<u64>,
+    /// Doctors curing players with `/treat`. The command is refused if unset
+    pub treatment: Option<TreatmentConfig>,
+    /// The name of the strain spreading in this game, so vaccines for other strains don't
+    /// protect against it
+    pub strain: Option<String>,
+    /// Vaccinating players with `/vaccinate`. The command is refused if unset
+    pub vaccination: Option<VaccinationConfig>,
+    /// How often to snapshot the game state (seconds, at least 1). Snapshots are disabled if unset
+    pub snapshot_interval: Option<u64>,
+    /// How long to keep snapshots for (seconds). Kept forever if unset
+    pub snapshot_retention: Option<u64>,
+    /// Who can run each command, keyed by command name. Commands that aren't listed need
+    /// MANAGE_MESSAGES
+    #[serde(default)]
+    pub permissions: HashMap<String, CommandPermissions>,
+    /// Which channels the game runs in, and their multipliers
+    #[serde(default)]
+    pub channels: ChannelConfig,
+    /// Where and how to announce infections and cures. Nothing is announced if unset
+    pub announcements: Option<AnnouncementConfig>,
+}
+
+/// Each vector is disabled if unset
+#[derive(serde::Deserialize, Clone, Default)]
+pub struct VectorsConfig {
+    /// Replying to an infected player's message
+    pub reply: Option<VectorConfig>,
+    /// Mentioning an infected player
+    pub mention: Option<VectorConfig>,
+    /// Being mentioned by an infected player
+    pub mentioned: Option<VectorConfig>,
+}
+
+#[derive(serde::Deserialize, Clone, Copy, Default)]
+pub struct VectorConfig {
+    /// The chance of the infection spreading, from 0 to 1 (default 1)
+    pub probability: Option<f64>,
+    /// The minimum amount of time between infections from one person through this vector
+    /// (seconds, defaults to `infection_cooldown`)
+    pub cooldown: Option<u32>,
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct ReactionConfig {
+    /// Emojis that can spread the infection when reacting to an infected player's message.
+    /// Custom emojis are given by id
+    #[serde(default)]
+    pub emojis: Vec<String>,
+    /// The chance and cooldown of reactions spreading the infection
+    #[serde(flatten)]
+    pub transmission: VectorConfig,
+    /// The emoji doctors react to an infected player's message with to help cure them
+    pub cure_emoji: Option<String>,
+    /// How many messages a cure reaction counts as towards curing (default 1)
+    pub cure_credit: Option<i64>,
+    /// The minimum amount of time between a doctor's cure reactions counting (seconds,
+    /// default 60)
+    pub cure_cooldown: Option<u32>,
+    /// How many of a doctor's cure reactions count in 24 hours. Unlimited if unset
+    pub cure_daily_uses: Option<u32>,
 }
 
 pub fn load(path: &std::path::Path) -> Result<Config> {
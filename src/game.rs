@@ -0,0 +1,1262 @@
+//! Every game state transition goes through a single actor task, so events are applied one at a
+//! time in the order they arrived. Without this, two messages in different channels could both
+//! read a player as healthy and both infect them, or both pass the same source's cooldown.
+//!
+//! Reads don't need to go through the actor - commands can read the [`PlayerStore`] directly.
+
+use std::{sync::Arc, time::Duration};
+
+use color_eyre::{Result, eyre::eyre};
+use poise::serenity_prelude::RoleId;
+use sqlx::SqlitePool;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::{
+    announcements::{Announcement, Announcer},
+    channels::{ChannelMultipliers, Proximity},
+    config::{GameConfig, VectorConfig},
+    helpers::{self, BoundedMap, MessageBuffer},
+    models::{InfectionEvent, InfectionRecord, Player, TransmissionVector},
+    notifications::{Notification, Notifier},
+    outbox::{Outbox, RoleAction, RoleChange},
+    participation::{Participation, Status},
+    players::{PlayerState, PlayerStore},
+    privacy,
+    rebuild::{self, Discrepancy},
+    revert::{self, Reversal, UndoError},
+    treatments::{self, Limits, TreatError, Treatment, TreatmentKind},
+    vaccination::{self, VaccinateError, Vaccination, Vaccine},
+    voice::Occupancy,
+};
+
+/// How many events can be waiting for the actor before senders have to wait
+const QUEUE_SIZE: usize = 1024;
+
+/// map of channel IDs to the last few users to message there
+pub type Channels = BoundedMap<u64, MessageBuffer<10>>;
+
+/// A message to be counted and checked for infections and cures
+pub struct IncomingMessage {
+    pub author: u64,
+    pub channel: u64,
+    pub message: u64,
+    pub timestamp: u64,
+    pub multipliers: ChannelMultipliers,
+    pub proximity: Proximity,
+    /// the author of the message this one replies to
+    pub replied_to: Option<u64>,
+    /// players mentioned in the message
+    pub mentions: Vec<u64>,
+}
+
+/// A reaction to another player's message
+pub struct IncomingReaction {
+    pub reactor: u64,
+    /// the author of the message that was reacted to
+    pub author: u64,
+    pub channel: u64,
+    pub multipliers: ChannelMultipliers,
+    pub kind: ReactionKind,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReactionKind {
+    /// Can infect the reactor if the author is infected
+    Transmit,
+    /// A doctor helping the author recover
+    Cure,
+}
+
+/// The outcome of [`GameHandle::infect_batch`]
+#[derive(Debug, Default)]
+pub struct BatchInfection {
+    pub infected: Vec<u64>,
+    /// targets that couldn't be infected because of an error, which is logged
+    pub failed: Vec<u64>,
+}
+
+enum GameEvent {
+    Message(IncomingMessage),
+    Reaction(IncomingReaction),
+    /// `elapsed` seconds have passed since the last tick
+    VoiceTick {
+        elapsed: i64,
+    },
+    Infect {
+        target: u64,
+        /// the target's roles, to check they play in opt-in games
+        roles: Vec<RoleId>,
+        moderator: u64,
+        reply: oneshot::Sender<Result<()>>,
+    },
+    Cure {
+        target: u64,
+        moderator: u64,
+        reply: oneshot::Sender<Result<()>>,
+    },
+    InfectBatch {
+        /// each target and their roles
+        targets: Vec<(u64, Vec<RoleId>)>,
+        reason: String,
+        moderator: u64,
+        reply: oneshot::Sender<BatchInfection>,
+    },
+    Undo {
+        record: i64,
+        moderator: u64,
+        reply: oneshot::Sender<Result<Result<Reversal, UndoError>>>,
+    },
+    Rollback {
+        since: i64,
+        moderator: u64,
+        reply: oneshot::Sender<Result<Vec<Reversal>>>,
+    },
+    SetParticipation {
+        player: u64,
+        status: Status,
+        reply: oneshot::Sender<Result<()>>,
+    },
+    DeleteData {
+        player: u64,
+        reply: oneshot::Sender<Result<String>>,
+    },
+    Vaccinate {
+        target: u64,
+        moderator: u64,
+        reply: oneshot::Sender<Result<Result<Vaccination, VaccinateError>>>,
+    },
+    Treat {
+        doctor: u64,
+        patient: u64,
+        channel: u64,
+        reply: oneshot::Sender<Result<Result<Treatment, TreatError>>>,
+    },
+    Rebuild {
+        repair: bool,
+        reply: oneshot::Sender<Result<Vec<Discrepancy>>>,
+    },
+    /// Stops the actor once everything queued has been handled
+    Shutdown {
+        reply: oneshot::Sender<()>,
+    },
+}
+
+/// Used to send events to the game actor
+#[derive(Clone)]
+pub struct GameHandle(mpsc::Sender<GameEvent>);
+
+impl GameHandle {
+    /// Queues a message to be counted and checked for infections/cures. This doesn't wait for
+    /// the message to be handled.
+    pub async fn message(&self, message: IncomingMessage) {
+        self.send(GameEvent::Message(message)).await;
+    }
+
+    /// Queues a reaction to be checked for infections/cures, without waiting for it to be
+    /// handled
+    pub async fn reaction(&self, reaction: IncomingReaction) {
+        self.send(GameEvent::Reaction(reaction)).await;
+    }
+
+    /// Infects the target, as long as they play. `roles` are the target's roles, which only
+    /// matter in opt-in games
+    pub async fn infect(&self, target: u64, roles: Vec<RoleId>, moderator: u64) -> Result<()> {
+        let (reply, rx) = oneshot::channel();
+        self.send(GameEvent::Infect {
+            target,
+            roles,
+            moderator,
+            reply,
+        })
+        .await;
+        rx.await?
+    }
+
+    pub async fn cure(&self, target: u64, moderator: u64) -> Result<()> {
+        let (reply, rx) = oneshot::channel();
+        self.send(GameEvent::Cure {
+            target,
+            moderator,
+            reply,
+        })
+        .await;
+        rx.await?
+    }
+
+    /// Infects every target that plays and isn't already infected, all with the same reason.
+    /// Targets come with their roles, like [`Self::infect`]. A target that fails doesn't stop the
+    /// rest from being infected.
+    pub async fn infect_batch(
+        &self,
+        targets: Vec<(u64, Vec<RoleId>)>,
+        reason: String,
+        moderator: u64,
+    ) -> Result<BatchInfection> {
+        let (reply, rx) = oneshot::channel();
+        self.send(GameEvent::InfectBatch {
+            targets,
+            reason,
+            moderator,
+            reply,
+        })
+        .await;
+        Ok(rx.await?)
+    }
+
+    /// Reverts a single infection record. See [`revert::plan_undo`].
+    pub async fn undo(&self, record: i64, moderator: u64) -> Result<Result<Reversal, UndoError>> {
+        let (reply, rx) = oneshot::channel();
+        self.send(GameEvent::Undo {
+            record,
+            moderator,
+            reply,
+        })
+        .await;
+        rx.await?
+    }
+
+    /// Reverts every infection record after `since`, returning what was reverted
+    pub async fn rollback(&self, since: i64, moderator: u64) -> Result<Vec<Reversal>> {
+        let (reply, rx) = oneshot::channel();
+        self.send(GameEvent::Rollback {
+            since,
+            moderator,
+            reply,
+        })
+        .await;
+        rx.await?
+    }
+
+    /// Joins or opts the player out of the game. Opting out also cures them.
+    pub async fn set_participation(&self, player: u64, status: Status) -> Result<()> {
+        let (reply, rx) = oneshot::channel();
+        self.send(GameEvent::SetParticipation {
+            player,
+            status,
+            reply,
+        })
+        .await;
+        rx.await?
+    }
+
+    /// Vaccinates the player with a dose from the pool. See [`vaccination`].
+    pub async fn vaccinate(
+        &self,
+        target: u64,
+        moderator: u64,
+    ) -> Result<Result<Vaccination, VaccinateError>> {
+        let (reply, rx) = oneshot::channel();
+        self.send(GameEvent::Vaccinate {
+            target,
+            moderator,
+            reply,
+        })
+        .await;
+        rx.await?
+    }
+
+    /// A doctor trying to cure a patient in `channel`. See [`treatments`].
+    pub async fn treat(
+        &self,
+        doctor: u64,
+        patient: u64,
+        channel: u64,
+    ) -> Result<Result<Treatment, TreatError>> {
+        let (reply, rx) = oneshot::channel();
+        self.send(GameEvent::Treat {
+            doctor,
+            patient,
+            channel,
+            reply,
+        })
+        .await;
+        rx.await?
+    }
+
+    /// Cures the player and deletes their data. See [`privacy::delete`].
+    pub async fn delete_data(&self, player: u64) -> Result<String> {
+        let (reply, rx) = oneshot::channel();
+        self.send(GameEvent::DeleteData { player, reply }).await;
+        rx.await?
+    }
+
+    /// See [`rebuild::rebuild`]. Repairs also update the in-memory state.
+    pub async fn rebuild(&self, repair: bool) -> Result<Vec<Discrepancy>> {
+        let (reply, rx) = oneshot::channel();
+        self.send(GameEvent::Rebuild { repair, reply }).await;
+        rx.await?
+    }
+
+    /// Handles everything already queued, then stops the actor so nothing changes after the
+    /// final flush. Events sent afterwards are dropped
+    pub async fn shutdown(&self) {
+        let (reply, rx) = oneshot::channel();
+        self.send(GameEvent::Shutdown { reply }).await;
+        let _ = rx.await;
+    }
+
+    async fn send(&self, event: GameEvent) {
+        // the actor only stops on shutdown, or if it panics, which is a bug anyway
+        if self.0.send(event).await.is_err() {
+            error!("Game actor has stopped, dropping event");
+        }
+    }
+}
+
+pub struct Game {
+    pub config: GameConfig,
+    pub players: Arc<PlayerStore>,
+    pub channels: Arc<Channels>,
+    pub outbox: Outbox,
+    pub announcer: Announcer,
+    pub notifier: Notifier,
+    pub participation: Arc<Participation>,
+    pub voice: Arc<Occupancy>,
+    pub pool: SqlitePool,
+}
+
+impl Game {
+    /// Spawns the actor, returning the handle used to talk to it
+    pub fn spawn(self) -> GameHandle {
+        let (tx, mut rx) = mpsc::channel(QUEUE_SIZE);
+
+        if let Some(voice) = &self.config.voice {
+            let elapsed = voice.interval.unwrap_or(60).max(1);
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(Duration::from_secs(elapsed.into()));
+                // the first tick completes immediately
+                interval.tick().await;
+                loop {
+                    interval.tick().await;
+                    let event = GameEvent::VoiceTick {
+                        elapsed: elapsed.into(),
+                    };
+                    if tx.send(event).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+
+        tokio::spawn(async move {
+            while let Some(event) = rx.recv().await {
+                if let GameEvent::Shutdown { reply } = event {
+                    // refuses new events, but still handles the ones already queued
+                    rx.close();
+                    while let Some(event) = rx.recv().await {
+                        self.handle(event).await;
+                    }
+                    let _ = reply.send(());
+                    break;
+                }
+                self.handle(event).await;
+            }
+        });
+
+        GameHandle(tx)
+    }
+
+    async fn handle(&self, event: GameEvent) {
+        match event {
+            GameEvent::Message(msg) => {
+                let id = msg.message;
+                if let Err(e) = self.on_message(msg).await {
+                    error!("Failed to handle message {}: {:?}", id, e);
+                }
+            }
+            GameEvent::Reaction(reaction) => {
+                let reactor = reaction.reactor;
+                if let Err(e) = self.on_reaction(reaction).await {
+                    error!("Failed to handle reaction from {}: {:?}", reactor, e);
+                }
+            }
+            GameEvent::VoiceTick { elapsed } => {
+                if let Err(e) = self.on_voice_tick(elapsed).await {
+                    error!("Failed to check voice channels: {:?}", e);
+                }
+            }
+            GameEvent::Infect {
+                target,
+                roles,
+                moderator,
+                reply,
+            } => {
+                if self.participation.opted_out(target) {
+                    let _ = reply.send(Err(eyre!("<@{}> has opted out of the game", target)));
+                    return;
+                }
+                if !self.participation.plays(target, &roles) {
+                    let _ = reply.send(Err(eyre!("<@{}> isn't playing the game", target)));
+                    return;
+                }
+
+                let reason = format!("Manually infected by <@{}>", moderator);
+                let result = self
+                    .transition(target, true, Some(moderator), None, reason)
+                    .await;
+                if result.as_ref().is_ok_and(|old| !old.infected) {
+                    self.announcer
+                        .announce(Announcement::InfectedManually { target });
+                    self.notifier
+                        .notify(target, Notification::Infected { channel: None });
+                }
+                let _ = reply.send(result.map(|_| ()));
+            }
+            GameEvent::Cure {
+                target,
+                moderator,
+                reply,
+            } => {
+                let reason = format!("Manually cured by <@{}>", moderator);
+                let result = self
+                    .transition(target, false, Some(moderator), None, reason)
+                    .await;
+                if let Ok(old) = &result
+                    && old.infected
+                {
+                    self.after_cure(target, old, true);
+                }
+                let _ = reply.send(result.map(|_| ()));
+            }
+            GameEvent::InfectBatch {
+                targets,
+                reason,
+                moderator,
+                reply,
+            } => {
+                let _ = reply.send(self.infect_batch(targets, reason, moderator).await);
+            }
+            GameEvent::Undo {
+                record,
+                moderator,
+                reply,
+            } => {
+                let _ = reply.send(self.undo(record, moderator).await);
+            }
+            GameEvent::Rollback {
+                since,
+                moderator,
+                reply,
+            } => {
+                let _ = reply.send(self.rollback(since, moderator).await);
+            }
+            GameEvent::SetParticipation {
+                player,
+                status,
+                reply,
+            } => {
+                let _ = reply.send(self.set_participation(player, status).await);
+            }
+            GameEvent::DeleteData { player, reply } => {
+                let _ = reply.send(self.delete_data(player).await);
+            }
+            GameEvent::Vaccinate {
+                target,
+                moderator,
+                reply,
+            } => {
+                let _ = reply.send(self.vaccinate(target, moderator).await);
+            }
+            GameEvent::Treat {
+                doctor,
+                patient,
+                channel,
+                reply,
+            } => {
+                let _ = reply.send(self.treat(doctor, patient, channel).await);
+            }
+            GameEvent::Rebuild { repair, reply } => {
+                let _ = reply.send(self.rebuild(repair).await);
+            }
+            // handled by the actor loop
+            GameEvent::Shutdown { reply } => {
+                let _ = reply.send(());
+            }
+        }
+    }
+
+    async fn on_message(&self, msg: IncomingMessage) -> Result<()> {
+        let player_id = msg.author;
+
+        // messages queued before the player opted out
+        if self.participation.opted_out(player_id) {
+            return Ok(());
+        }
+
+        let now = helpers::now() as i64;
+
+        // only infected players make progress towards curing
+        let credit = match self.players.get(player_id).is_some_and(|p| p.infected) {
+            true => cure_credit(msg.multipliers.cure),
+            false => 0,
+        };
+
+        // counts the message, but only towards curing if the cooldown has passed
+        let (player, credited) =
+            self.players
+                .record_message(player_id, now, self.config.message_cooldown, credit);
+
+        trace!(
+            "player {} has {} messages ({} sanitized)",
+            player_id, player.total_messages, player.sanitized_messages,
+        );
+
+        let last_message = {
+            let buf = self.channels.get_or_insert(&msg.proximity.buffer);
+            let mut buf = buf.lock().await;
+            let last_message = buf.get_last_message();
+            buf.push(player_id, msg.message, msg.timestamp);
+            last_message
+        };
+
+        // the first reply in a thread is next to whoever started it
+        let last_author = match (last_message, msg.proximity.starter) {
+            (Some((author, ..)), _) => Some(author),
+            (None, Some(starter)) => {
+                let from_message = match self.channels.get(&starter.parent) {
+                    Some(buf) => buf
+                        .lock()
+                        .await
+                        .iter()
+                        .find(|(_, id, _)| *id == starter.message)
+                        .map(|(author, ..)| author),
+                    None => None,
+                };
+                Some(from_message.unwrap_or(starter.owner))
+            }
+            (None, None) => None,
+        };
+
+        if player.infected {
+            trace!("player is already infected, checking if they need to be cured");
+            self.check_cure(player_id, &player, credited, now).await?;
+            // they can still spread to anyone they mentioned
+            return self.spread_to_mentions(&msg, now).await;
+        }
+
+        trace!("player is not infected, checking if they should be");
+
+        // last_author may not actually exist if the message was sent before the bot started;
+        // if not, they cannot be infected by proximity anyway
+        let sources = last_author
+            .map(|a| (TransmissionVector::Proximity, a))
+            .into_iter()
+            .chain(msg.replied_to.map(|r| (TransmissionVector::Reply, r)))
+            .chain(
+                msg.mentions
+                    .iter()
+                    .map(|m| (TransmissionVector::Mention, *m)),
+            );
+
+        let mut exposed = false;
+        for (vector, source_id) in sources {
+            let Some(source) = self.players.get(source_id) else {
+                continue;
+            };
+            if source_id == player_id || !source.infected {
+                continue;
+            }
+            let Some(probability) = self.transmission_probability(vector) else {
+                continue;
+            };
+
+            // sources that have infected someone within the cooldown only expose the player
+            if !self.off_cooldown(&source, vector, msg.multipliers, now) {
+                exposed |= vector == TransmissionVector::Proximity;
+                continue;
+            }
+
+            if rand::random::<f64>() < probability {
+                return self
+                    .infect_by(player_id, source_id, vector, msg.channel, now)
+                    .await;
+            }
+        }
+
+        if exposed {
+            self.notifier.notify(
+                player_id,
+                Notification::Exposed {
+                    channel: msg.channel,
+                },
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Infects the players an infected author mentioned, while the author is off cooldown
+    async fn spread_to_mentions(&self, msg: &IncomingMessage, now: i64) -> Result<()> {
+        let vector = TransmissionVector::Mentioned;
+        let Some(probability) = self.transmission_probability(vector) else {
+            return Ok(());
+        };
+
+        for &target in &msg.mentions {
+            // fetched every time, since each infection starts the cooldown, and they may have just
+            // been cured
+            let author = self.players.get(msg.author).unwrap_or_default();
+            if !author.infected || !self.off_cooldown(&author, vector, msg.multipliers, now) {
+                break;
+            }
+
+            if target == msg.author
+                || self.participation.opted_out(target)
+                || self.players.get(target).is_some_and(|p| p.infected)
+            {
+                continue;
+            }
+
+            if rand::random::<f64>() < probability {
+                self.infect_by(target, msg.author, vector, msg.channel, now)
+                    .await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn on_reaction(&self, reaction: IncomingReaction) -> Result<()> {
+        let Some(config) = &self.config.reactions else {
+            return Ok(());
+        };
+        let IncomingReaction {
+            reactor, author, ..
+        } = reaction;
+
+        // reactions queued before either player opted out
+        if self.participation.opted_out(reactor) || self.participation.opted_out(author) {
+            return Ok(());
+        }
+
+        let now = helpers::now() as i64;
+        let author_state = self.players.get(author).unwrap_or_default();
+        if !author_state.infected {
+            return Ok(());
+        }
+
+        match reaction.kind {
+            ReactionKind::Transmit => {
+                let vector = TransmissionVector::Reaction;
+                if self.players.get(reactor).is_some_and(|p| p.infected)
+                    || !self.off_cooldown(&author_state, vector, reaction.multipliers, now)
+                {
+                    return Ok(());
+                }
+
+                let probability = config.transmission.probability.unwrap_or(1.0);
+                if rand::random::<f64>() < probability {
+                    self.infect_by(reactor, author, vector, reaction.channel, now)
+                        .await?;
+                }
+            }
+            ReactionKind::Cure => {
+                let kind = TreatmentKind::Reaction;
+                let limits = Limits::reaction(config);
+                let mut tx = self.pool.begin().await?;
+                if treatments::check(&limits, kind, reactor, now, &mut tx)
+                    .await?
+                    .is_err()
+                {
+                    return Ok(());
+                }
+                treatments::save(reactor, author, now, kind, true, &mut tx).await?;
+                tx.commit().await?;
+
+                let credit = config.cure_credit.unwrap_or(1);
+                let player = self.players.record_treatment(author, credit);
+
+                debug!("Player {} treated {} by reacting", reactor, author);
+                self.check_cure(author, &player, credit, now).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Exposes members to the infected members in their voice channel, infecting the ones that
+    /// have been exposed for long enough
+    async fn on_voice_tick(&self, elapsed: i64) -> Result<()> {
+        let Some(config) = &self.config.voice else {
+            return Ok(());
+        };
+        let vector = TransmissionVector::Voice;
+        let exposure = config.exposure.unwrap_or(5 * 60) as i64;
+        let probability = config.transmission.probability.unwrap_or(1.0);
+        let now = helpers::now() as i64;
+
+        for room in self.voice.rooms() {
+            let (infected, healthy): (Vec<_>, Vec<_>) = room
+                .members
+                .iter()
+                .filter(|&&m| !self.participation.opted_out(m))
+                .map(|&m| (m, self.players.get(m).unwrap_or_default()))
+                .partition(|(_, p)| p.infected);
+            if infected.is_empty() {
+                continue;
+            }
+
+            let strain = self.config.strain.as_deref();
+            for (target, player) in healthy {
+                // vaccinated members can't catch it, so they aren't exposed either
+                if player.immune(strain, now) {
+                    continue;
+                }
+                if self.voice.expose(target, elapsed) < exposure {
+                    continue;
+                }
+
+                // fetched every time, since each infection starts the source's cooldown
+                let Some(source) = infected.iter().map(|(id, _)| *id).find(|&id| {
+                    let source = self.players.get(id).unwrap_or_default();
+                    source.infected && self.off_cooldown(&source, vector, room.multipliers, now)
+                }) else {
+                    // the exposure keeps adding up until someone can spread it
+                    continue;
+                };
+
+                self.voice.reset_exposure(target);
+                if rand::random::<f64>() < probability {
+                    self.infect_by(target, source, vector, room.channel, now)
+                        .await?;
+                } else {
+                    self.notifier.notify(
+                        target,
+                        Notification::Exposed {
+                            channel: room.channel,
+                        },
+                    );
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The chance of an infection spreading through `vector`, or `None` if it's disabled
+    fn transmission_probability(&self, vector: TransmissionVector) -> Option<f64> {
+        let config = self.vector_config(vector)?;
+        Some(config.probability.unwrap_or(1.0))
+    }
+
+    /// The config for `vector`, or `None` if it's disabled. Proximity is always enabled.
+    fn vector_config(&self, vector: TransmissionVector) -> Option<VectorConfig> {
+        let vectors = &self.config.vectors;
+        match vector {
+            TransmissionVector::Proximity => Some(VectorConfig::default()),
+            TransmissionVector::Reply => vectors.reply,
+            TransmissionVector::Mention => vectors.mention,
+            TransmissionVector::Mentioned => vectors.mentioned,
+            TransmissionVector::Reaction => self.config.reactions.as_ref().map(|r| r.transmission),
+            TransmissionVector::Voice => self.config.voice.as_ref().map(|v| v.transmission),
+            // only spreads through `/treat`, which has its own config
+            TransmissionVector::Treatment => None,
+        }
+    }
+
+    /// Whether `source` can infect someone through `vector`. The cooldown is shorter in channels
+    /// that spread faster.
+    fn off_cooldown(
+        &self,
+        source: &PlayerState,
+        vector: TransmissionVector,
+        multipliers: ChannelMultipliers,
+        now: i64,
+    ) -> bool {
+        let cooldown = self
+            .vector_config(vector)
+            .and_then(|v| v.cooldown)
+            .unwrap_or(self.config.infection_cooldown) as f64
+            / multipliers.transmission;
+
+        multipliers.transmission > 0.0
+            && source
+                .last_transmission(vector)
+                .is_none_or(|t| (now - t) as f64 > cooldown)
+    }
+
+    async fn infect_by(
+        &self,
+        target: u64,
+        source: u64,
+        vector: TransmissionVector,
+        channel: u64,
+        now: i64,
+    ) -> Result<()> {
+        if self.immune(target, now) {
+            debug!("Player {} is immune to {}", target, source);
+            return Ok(());
+        }
+
+        info!("Player {} infected by {} ({:?})", target, source, vector);
+
+        let reason = format!("Infected by {} <@{}>", vector.describe(), source);
+        self.transition(target, true, Some(source), Some(vector), reason)
+            .await?;
+        self.players.record_transmission(source, vector, now);
+        self.announcer.announce(Announcement::Infected {
+            target,
+            source,
+            vector,
+            channel,
+        });
+        // voice and treatment infections don't happen in a text channel worth linking to
+        let channel = match vector {
+            TransmissionVector::Voice | TransmissionVector::Treatment => None,
+            _ => Some(channel),
+        };
+        self.notifier
+            .notify(target, Notification::Infected { channel });
+
+        Ok(())
+    }
+
+    /// Whether the player's vaccine protects them from this game's strain
+    fn immune(&self, player: u64, now: i64) -> bool {
+        self.players
+            .get(player)
+            .is_some_and(|p| p.immune(self.config.strain.as_deref(), now))
+    }
+
+    /// Cures the player if they have sent enough messages or been infected for long enough.
+    /// `credited` is how much their latest message counted towards curing.
+    async fn check_cure(
+        &self,
+        player_id: u64,
+        player: &PlayerState,
+        credited: i64,
+        now: i64,
+    ) -> Result<()> {
+        let infected_at = player.infected_at.unwrap_or(now);
+        let progress = player.cure_progress;
+
+        // FIXME: move timeout checking out of this function - just sweep every few minutes instead?
+        let cure_reason = if progress > self.config.cure_threshold.into() {
+            format!(
+                "Sent {} messages while infected",
+                self.config.cure_threshold
+            )
+        } else if self
+            .config
+            .cure_timeout
+            .is_some_and(|t| now - infected_at > t as i64)
+        {
+            format!(
+                "Was infected for more than {} seconds",
+                self.config.cure_timeout.unwrap()
+            )
+        } else {
+            // only notified once, on the message that took them past the mark. curing takes one
+            // more message than the threshold
+            let remaining = self.config.cure_threshold as i64 + 1 - progress;
+            let nearing = (self.config.cure_threshold as i64 / 4).max(1);
+            if remaining <= nearing && remaining + credited > nearing {
+                self.notifier
+                    .notify(player_id, Notification::NearingCure { remaining });
+            }
+            return Ok(());
+        };
+
+        info!("Player {} cured", player_id);
+
+        self.transition(player_id, false, None, None, cure_reason)
+            .await?;
+        self.after_cure(player_id, player, false);
+
+        Ok(())
+    }
+
+    /// Tells the player and the announcement channel about a cure
+    fn after_cure(&self, target: u64, old: &PlayerState, manually: bool) {
+        self.notifier.notify(target, Notification::Cured);
+
+        let now = helpers::now() as i64;
+        self.announcer.announce(Announcement::Cured {
+            target,
+            messages: old.sanitized_messages - old.infected_sanitized_messages,
+            duration: old.infected_at.map_or(0, |t| now - t),
+            manually,
+        });
+    }
+
+    /// Infects or cures a player: saves their state, the infection record and the role change
+    /// in one transaction, then applies it in memory. Returns the player's state from before.
+    async fn transition(
+        &self,
+        target: u64,
+        infected: bool,
+        source: Option<u64>,
+        vector: Option<TransmissionVector>,
+        reason: String,
+    ) -> Result<PlayerState> {
+        let now = helpers::now() as i64;
+        let old = self.players.get(target).unwrap_or_default();
+        let player = old.with_infected(infected, now);
+
+        let target_str = target.to_string();
+        let source = source.map(|s| s.to_string());
+        let mut tx = self.pool.begin().await?;
+
+        // the player's row may not have been flushed yet, so this writes all of it
+        player.save(target, &mut *tx).await?;
+        if let Some(source) = &source {
+            Player::create_if_missing(source, &mut *tx).await?;
+        }
+
+        InfectionRecord {
+            id: 0,
+            event: match infected {
+                true => InfectionEvent::Infected,
+                false => InfectionEvent::Cured,
+            },
+            target: target_str.clone(),
+            source,
+            reason: Some(reason),
+            recorded_at: now,
+            target_total_messages: player.total_messages,
+            target_sanitized_messages: player.sanitized_messages,
+            reverts: None,
+            vector,
+        }
+        .save(&mut *tx)
+        .await?;
+
+        let action = match infected {
+            true => RoleAction::Add,
+            false => RoleAction::Remove,
+        };
+        RoleChange::new(target_str, self.config.infected_role, action)
+            .enqueue(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+
+        self.players.set_infected(target, infected, now);
+        if infected {
+            // time spent in voice before being infected shouldn't count once they've recovered
+            self.voice.reset_exposure(target);
+        }
+        self.outbox.wake();
+
+        Ok(old)
+    }
+
+    async fn infect_batch(
+        &self,
+        targets: Vec<(u64, Vec<RoleId>)>,
+        reason: String,
+        moderator: u64,
+    ) -> BatchInfection {
+        let mut batch = BatchInfection::default();
+        for (target, roles) in targets {
+            if self.players.get(target).is_some_and(|p| p.infected)
+                || !self.participation.plays(target, &roles)
+            {
+                continue;
+            }
+
+            // each target is its own transaction, so earlier ones stay infected
+            if let Err(e) = self
+                .transition(target, true, Some(moderator), None, reason.clone())
+                .await
+            {
+                error!("Failed to bulk infect {}: {:?}", target, e);
+                batch.failed.push(target);
+                continue;
+            }
+            self.announcer
+                .announce(Announcement::InfectedManually { target });
+            self.notifier
+                .notify(target, Notification::Infected { channel: None });
+            batch.infected.push(target);
+        }
+
+        info!(
+            "Bulk infected {} players ({} failed): {}",
+            batch.infected.len(),
+            batch.failed.len(),
+            reason
+        );
+
+        batch
+    }
+
+    async fn undo(&self, record: i64, moderator: u64) -> Result<Result<Reversal, UndoError>> {
+        // planned again here in case anything changed since the preview
+        let reversal = match revert::plan_undo(&self.pool, record).await? {
+            Ok(r) => r,
+            Err(e) => return Ok(Err(e)),
+        };
+
+        let reason = format!("Record #{} undone by <@{}>", record, moderator);
+        self.revert(&reversal, moderator, &reason).await?;
+
+        info!("Undid infection record {}", record);
+
+        Ok(Ok(reversal))
+    }
+
+    async fn rollback(&self, since: i64, moderator: u64) -> Result<Vec<Reversal>> {
+        let reversals = revert::plan_rollback(&self.pool, since).await?;
+
+        let reason = format!("Rolled back to <t:{}:f> by <@{}>", since, moderator);
+        for reversal in &reversals {
+            self.revert(reversal, moderator, &reason).await?;
+        }
+
+        info!("Rolled back {} players to {}", reversals.len(), since);
+
+        Ok(reversals)
+    }
+
+    /// Writes a compensating record for each reverted record and puts the player back in the
+    /// state they were in before the first one. Restoring an infection restarts its cure clock.
+    async fn revert(&self, reversal: &Reversal, moderator: u64, reason: &str) -> Result<()> {
+        let now = helpers::now() as i64;
+        let target: u64 = reversal.target.parse()?;
+        let infected = reversal.infected();
+
+        let current = self.players.get(target).unwrap_or_default();
+        let changed = current.infected != infected;
+        let player = match changed {
+            true => current.with_infected(infected, now),
+            false => current,
+        };
+
+        let moderator = moderator.to_string();
+        let mut tx = self.pool.begin().await?;
+
+        player.save(target, &mut *tx).await?;
+        Player::create_if_missing(&moderator, &mut *tx).await?;
+
+        for (record, before) in &reversal.records {
+            InfectionRecord {
+                id: 0,
+                event: match before {
+                    true => InfectionEvent::Infected,
+                    false => InfectionEvent::Cured,
+                },
+                target: reversal.target.clone(),
+                source: Some(moderator.clone()),
+                reason: Some(reason.to_string()),
+                recorded_at: now,
+                target_total_messages: player.total_messages,
+                target_sanitized_messages: player.sanitized_messages,
+                reverts: Some(record.id),
+                vector: None,
+            }
+            .save(&mut *tx)
+            .await?;
+        }
+
+        // queued even if nothing changed, in case the role had drifted
+        let action = match infected {
+            true => RoleAction::Add,
+            false => RoleAction::Remove,
+        };
+        RoleChange::new(&reversal.target, self.config.infected_role, action)
+            .enqueue(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+
+        if changed {
+            self.players.set_infected(target, infected, now);
+            if infected {
+                self.voice.reset_exposure(target);
+            }
+        }
+        self.outbox.wake();
+
+        Ok(())
+    }
+
+    async fn set_participation(&self, player: u64, status: Status) -> Result<()> {
+        self.participation.set(player, status, &self.pool).await?;
+
+        if status == Status::OptedOut && self.players.get(player).is_some_and(|p| p.infected) {
+            let reason = "Opted out of the game".to_string();
+            self.transition(player, false, None, None, reason).await?;
+        }
+
+        info!("Player {} is now {:?}", player, status);
+
+        Ok(())
+    }
+
+    async fn treat(
+        &self,
+        doctor: u64,
+        patient: u64,
+        channel: u64,
+    ) -> Result<Result<Treatment, TreatError>> {
+        let Some(config) = &self.config.treatment else {
+            return Err(eyre!("Treatment isn't enabled"));
+        };
+
+        if doctor == patient {
+            return Ok(Err(TreatError::SelfTreatment));
+        }
+        if self.participation.opted_out(patient) {
+            return Ok(Err(TreatError::OptedOut));
+        }
+        if !self.players.get(patient).is_some_and(|p| p.infected) {
+            return Ok(Err(TreatError::NotInfected));
+        }
+
+        let now = helpers::now() as i64;
+        let kind = TreatmentKind::Command;
+        let limits = Limits::command(config);
+        let mut tx = self.pool.begin().await?;
+        let uses_left = match treatments::check(&limits, kind, doctor, now, &mut tx).await? {
+            Ok(uses_left) => uses_left,
+            Err(e) => return Ok(Err(e)),
+        };
+
+        let cured = rand::random::<f64>() < config.probability.unwrap_or(0.5);
+        treatments::save(doctor, patient, now, kind, cured, &mut tx).await?;
+        tx.commit().await?;
+        info!(
+            "Player {} treated {} ({})",
+            doctor,
+            patient,
+            if cured { "cured" } else { "failed" }
+        );
+
+        // caught before the cure, while the patient is still infected
+        let doctor_infected = !self.participation.opted_out(doctor)
+            && !self.players.get(doctor).is_some_and(|p| p.infected)
+            && !self.immune(doctor, now)
+            && rand::random::<f64>() < config.exposure.unwrap_or(0.2);
+        if doctor_infected {
+            self.infect_by(doctor, patient, TransmissionVector::Treatment, channel, now)
+                .await?;
+        }
+
+        if cured {
+            // a cure's source is the moderator who made it, so the doctor is only in the reason
+            let reason = format!("Treated by <@{}>", doctor);
+            let old = self.transition(patient, false, None, None, reason).await?;
+            self.after_cure(patient, &old, false);
+        }
+
+        Ok(Ok(Treatment {
+            cured,
+            doctor_infected,
+            uses_left,
+        }))
+    }
+
+    async fn vaccinate(
+        &self,
+        target: u64,
+        moderator: u64,
+    ) -> Result<Result<Vaccination, VaccinateError>> {
+        let Some(config) = &self.config.vaccination else {
+            return Err(eyre!("Vaccination isn't enabled"));
+        };
+
+        let now = helpers::now() as i64;
+        let player = self.players.get(target).unwrap_or_default();
+        if self.participation.opted_out(target) {
+            return Ok(Err(VaccinateError::OptedOut));
+        }
+        if player.infected {
+            return Ok(Err(VaccinateError::Infected));
+        }
+        if let Some(vaccine) = &player.vaccine
+            && vaccine.protects(self.config.strain.as_deref(), now)
+        {
+            return Ok(Err(VaccinateError::AlreadyImmune {
+                until: vaccine.immune_until,
+            }));
+        }
+
+        let mut tx = self.pool.begin().await?;
+        let doses_left = match vaccination::take_dose(config, now, &mut tx).await? {
+            Ok(doses_left) => doses_left,
+            Err(refill_at) => return Ok(Err(VaccinateError::OutOfDoses { refill_at })),
+        };
+
+        let vaccine = Vaccine {
+            vaccinated_at: now,
+            immune_until: config.immunity.map(|i| now + i as i64),
+            strain: config.strain.clone(),
+        };
+        let (target_str, moderator_str) = (target.to_string(), moderator.to_string());
+        vaccine.save(&target_str, &mut tx).await?;
+        Player::create_if_missing(&moderator_str, &mut *tx).await?;
+        InfectionRecord {
+            id: 0,
+            event: InfectionEvent::Vaccinated,
+            target: target_str,
+            source: Some(moderator_str),
+            reason: Some(format!("Vaccinated by <@{}>", moderator)),
+            recorded_at: now,
+            target_total_messages: player.total_messages,
+            target_sanitized_messages: player.sanitized_messages,
+            reverts: None,
+            vector: None,
+        }
+        .save(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        info!("Player {} vaccinated by {}", target, moderator);
+
+        let immune_until = vaccine.immune_until;
+        self.players.set_vaccine(target, vaccine);
+
+        Ok(Ok(Vaccination {
+            immune_until,
+            doses_left,
+        }))
+    }
+
+    async fn delete_data(&self, player: u64) -> Result<String> {
+        // otherwise their pseudonym would stay infected forever, and they'd keep the role
+        if self.players.get(player).is_some_and(|p| p.infected) {
+            let reason = "Deleted their data".to_string();
+            self.transition(player, false, None, None, reason).await?;
+        }
+
+        // forgotten first so their counters aren't flushed back
+        self.players.remove(player);
+        self.voice.forget(player);
+
+        let mut tx = self.pool.begin().await?;
+        let pseudonym = privacy::delete(&mut tx, player).await?;
+        tx.commit().await?;
+
+        info!("Deleted the data of a player, now {}", pseudonym);
+
+        Ok(pseudonym)
+    }
+
+    async fn rebuild(&self, repair: bool) -> Result<Vec<Discrepancy>> {
+        // players that haven't been flushed yet would otherwise be missed
+        self.players.flush(&self.pool).await?;
+
+        let discrepancies = rebuild::rebuild(&self.pool, repair, self.config.infected_role).await?;
+
+        if repair {
+            let now = helpers::now() as i64;
+            for d in &discrepancies {
+                if let Ok(id) = d.player.parse() {
+                    self.players.set_infected(id, d.records, now);
+                }
+            }
+            self.outbox.wake();
+        }
+
+        Ok(discrepancies)
+    }
+}
+
+/// How much a message counts towards curing with the channel's cure multiplier. Fractional
+/// multipliers count the extra message some of the time, e.g. 1.5 counts as 2 half the time.
+fn cure_credit(multiplier: f64) -> i64 {
+    let whole = multiplier.max(0.0).floor();
+    whole as i64 + (rand::random::<f64>() < multiplier - whole) as i64
+}
@@ -1,11 +1,7 @@
-use ::serenity::all::{CacheHttp, GuildId, UserId};
 use color_eyre::Result;
 use poise::serenity_prelude as serenity;
 
-use crate::{
-    helpers::{self},
-    models::{InfectionEvent, InfectionRecord},
-};
+use crate::game::{IncomingMessage, IncomingReaction, ReactionKind};
 
 pub async fn new_message(
     ctx: &serenity::Context,
@@ -16,192 +12,152 @@ pub async fn new_message(
         return Ok(());
     }
 
-    let Some(guild_id) = msg.guild_id else {
+    if msg.guild_id.is_none() {
         return Ok(());
-    };
+    }
 
-    let player_id = msg.author.id.to_string();
-
-    // inserts a new player or adds to the previous player's message count **only if** the cooldown
-    // has passed
-    // maybe should just be handled in rust for cleanliness' sake?
-    let player = sqlx::query!(
-        r#"
-        INSERT INTO players (id, total_messages, sanitized_messages) VALUES (?, 1, 1)
-        ON CONFLICT (id) DO UPDATE SET
-            total_messages = total_messages + 1,
-            sanitized_messages =
-                CASE WHEN unixepoch() - last_action > ?
-                THEN sanitized_messages + 1
-                ELSE sanitized_messages END,
-            last_action =
-                CASE WHEN unixepoch() - last_action > ?
-                THEN unixepoch()
-                ELSE last_action END
-        RETURNING total_messages, sanitized_messages
-        "#,
-        player_id,
-        data.game_config.message_cooldown,
-        data.game_config.message_cooldown,
-    )
-    .fetch_one(&data.db_pool)
-    .await?;
-
-    trace!(
-        "player {} has {} messages ({} sanitized)",
-        player_id, player.total_messages, player.sanitized_messages,
-    );
-
-    // TODO: add a cache for player infection state?
-    let player_is_infected = sqlx::query!("SELECT infected FROM players WHERE id = ?", player_id)
-        .fetch_optional(&data.db_pool)
-        .await?
-        .is_some_and(|p| p.infected);
-
-    if player_is_infected {
-        trace!("player is already infected, checking if they need to be cured");
-        return check_cure(ctx, data, msg.author.id, guild_id).await;
+    let roles = msg.member.as_ref().map_or(&[][..], |m| &m.roles);
+    if !data.participation.plays(msg.author.id.get(), roles) {
+        return Ok(());
     }
 
-    trace!("player is not infected, checking if they should be");
+    let channel = data.channel_rules.lookup(&ctx.http, msg.channel_id).await;
+    if !data.channel_rules.allows(&channel) {
+        return Ok(());
+    }
 
-    let last_message = {
-        let buf = data.channels.get_or_insert(&msg.channel_id.get()).await;
-        let mut buf = buf.lock().await;
-        let last_message = buf.get_last_message();
-        buf.push(
-            msg.author.id.get(),
-            msg.id.get(),
+    data.channel_activity.record(msg.channel_id.get());
+
+    let replied_to = msg
+        .referenced_message
+        .as_ref()
+        .filter(|m| !m.author.bot && m.author.id != msg.author.id)
+        .map(|m| m.author.id.get());
+
+    let mentions = msg
+        .mentions
+        .iter()
+        .filter(|u| !u.bot && u.id != msg.author.id)
+        .filter(|u| {
+            let roles = u.member.as_ref().map_or(&[][..], |m| &m.roles);
+            data.participation.plays(u.id.get(), roles)
+        })
+        .map(|u| u.id.get())
+        .collect();
+
+    // the game actor does the rest, in the order messages arrived
+    data.game
+        .message(IncomingMessage {
+            author: msg.author.id.get(),
+            channel: msg.channel_id.get(),
+            message: msg.id.get(),
             // why can't people settle on a standard type for unix timestamps :/
-            msg.timestamp.unix_timestamp().try_into().unwrap(),
-        );
-        last_message
+            timestamp: msg.timestamp.unix_timestamp().try_into().unwrap(),
+            multipliers: data.channel_rules.multipliers(&channel),
+            proximity: data.channel_rules.proximity(&channel),
+            replied_to,
+            mentions,
+        })
+        .await;
+
+    Ok(())
+}
+
+pub async fn reaction_add(
+    ctx: &serenity::Context,
+    data: &crate::Data,
+    reaction: &serenity::Reaction,
+) -> Result<()> {
+    let Some(config) = &data.game_config.reactions else {
+        return Ok(());
     };
 
-    // last_message may not actually exist if the message was sent before the bot started;
-    // if not, they cannot possibly be infected anyway
-    let author_data = match last_message {
-        Some(m) => {
-            let a = m.0.to_string();
-            sqlx::query!("SELECT id, infected FROM players WHERE id = ?", a)
-                .fetch_optional(&data.db_pool)
-                .await?
-        }
-        None => None,
+    let (Some(_), Some(reactor), Some(author), Some(member)) = (
+        reaction.guild_id,
+        reaction.user_id,
+        reaction.message_author_id,
+        &reaction.member,
+    ) else {
+        return Ok(());
     };
+    if member.user.bot || reactor == author {
+        return Ok(());
+    }
 
-    // only infect the player if the previous message is infected *and* they haven't infected
-    // anyone within the cooldown
-    let should_infect = match author_data {
-        Some(ref a) if a.infected => sqlx::query!(
-            "SELECT recorded_at FROM infection_records WHERE source = ? ORDER BY recorded_at DESC",
-            a.id
-        )
-        .fetch_optional(&data.db_pool)
-        .await?
-        .is_some_and(|r| {
-            helpers::now() - r.recorded_at as u64 > data.game_config.infection_cooldown as u64
-        }),
-        _ => false,
+    let emoji = match &reaction.emoji {
+        serenity::ReactionType::Custom { id, .. } => id.to_string(),
+        serenity::ReactionType::Unicode(emoji) => emoji.clone(),
+        _ => return Ok(()),
     };
+    let is_doctor = member
+        .roles
+        .iter()
+        .any(|r| data.game_config.doctor_roles.contains(&r.get()));
+    let kind = if config.emojis.contains(&emoji) {
+        ReactionKind::Transmit
+    } else if config.cure_emoji.as_ref() == Some(&emoji) && is_doctor {
+        ReactionKind::Cure
+    } else {
+        return Ok(());
+    };
+
+    if !data.participation.plays(reactor.get(), &member.roles) {
+        return Ok(());
+    }
 
-    if should_infect {
-        let author_data = author_data.unwrap();
-        info!("Player {} infected by {}", player_id, author_data.id);
-
-        // TODO: possibly just combine with the above query for updating message count
-        sqlx::query!("UPDATE players SET infected = true WHERE id = ?", player_id)
-            .execute(&data.db_pool)
-            .await?;
-
-        InfectionRecord {
-            event: InfectionEvent::Infected,
-            target: player_id,
-            source: Some(author_data.id.clone()),
-            reason: Some(format!("Infected by proximity to <@{}>", author_data.id)),
-            recorded_at: helpers::now() as i64,
-            target_total_messages: player.total_messages,
-            target_sanitized_messages: player.sanitized_messages,
-        }
-        .save(&data.db_pool)
-        .await?;
-
-        guild_id
-            .member(ctx.http(), msg.author.id)
-            .await?
-            .add_role(ctx.http(), data.game_config.infected_role)
-            .await?;
+    let channel = data
+        .channel_rules
+        .lookup(&ctx.http, reaction.channel_id)
+        .await;
+    if !data.channel_rules.allows(&channel) {
+        return Ok(());
     }
 
+    data.game
+        .reaction(IncomingReaction {
+            reactor: reactor.get(),
+            author: author.get(),
+            channel: reaction.channel_id.get(),
+            multipliers: data.channel_rules.multipliers(&channel),
+            kind,
+        })
+        .await;
+
     Ok(())
 }
 
-async fn check_cure(
+/// Tracks who's in each voice channel, for voice state updates and the voice states sent when the
+/// bot connects
+pub async fn voice_state(
     ctx: &serenity::Context,
     data: &crate::Data,
-    player_id: UserId,
-    guild_id: GuildId,
+    state: &serenity::VoiceState,
 ) -> Result<()> {
-    let player_id_str = player_id.to_string();
-    let player = sqlx::query!("SELECT * FROM players WHERE id = ?", player_id_str)
-        .fetch_one(&data.db_pool)
-        .await?;
-
-    let action = sqlx::query!(
-        "SELECT recorded_at, target_sanitized_messages FROM infection_records WHERE target = ? AND event = 'infected' ORDER BY recorded_at DESC",
-        player_id_str
-    )
-    .fetch_one(&data.db_pool)
-    .await?;
-
-    // FIXME: move timeout checking out of this function - just sweep every few minutes instead?
-    let cure_reason = if player.sanitized_messages - action.target_sanitized_messages
-        > data.game_config.cure_threshold.into()
-    {
-        format!(
-            "Sent {} messages while infected",
-            data.game_config.cure_threshold
-        )
-    } else if data
-        .game_config
-        .cure_timeout
-        .is_some_and(|t| helpers::now() - action.recorded_at as u64 > t)
+    if data.game_config.voice.is_none() {
+        return Ok(());
+    }
+
+    let member = state.user_id.get();
+    let roles = state.member.as_ref().map_or(&[][..], |m| &m.roles);
+    if state.member.as_ref().is_some_and(|m| m.user.bot) || !data.participation.plays(member, roles)
     {
-        format!(
-            "Was infected for more than {} seconds",
-            data.game_config.cure_timeout.unwrap()
-        )
-    } else {
+        return Ok(());
+    }
+
+    // deafened members can't hear anyone, so they're treated as having left
+    let channel = state.channel_id.filter(|_| !state.deaf && !state.self_deaf);
+    let Some(channel_id) = channel else {
+        data.voice.leave(member);
         return Ok(());
     };
 
-    info!("Player {} cured", player_id);
-
-    // TODO: possibly just combine with the above query for updating message count
-    sqlx::query!(
-        "UPDATE players SET infected = true WHERE id = ?",
-        player_id_str
-    )
-    .execute(&data.db_pool)
-    .await?;
-
-    InfectionRecord {
-        event: InfectionEvent::Infected,
-        target: player_id_str,
-        source: None,
-        reason: Some(cure_reason),
-        recorded_at: helpers::now() as i64,
-        target_total_messages: player.total_messages,
-        target_sanitized_messages: player.sanitized_messages,
+    let channel = data.channel_rules.lookup(&ctx.http, channel_id).await;
+    if data.channel_rules.allows(&channel) {
+        let multipliers = data.channel_rules.multipliers(&channel);
+        data.voice.join(member, channel_id.get(), multipliers);
+    } else {
+        data.voice.leave(member);
     }
-    .save(&data.db_pool)
-    .await?;
-
-    guild_id
-        .member(ctx.http(), player_id)
-        .await?
-        .add_role(ctx.http(), data.game_config.infected_role)
-        .await?;
 
     Ok(())
 }
@@ -1,12 +1,15 @@
 use std::{
     collections::HashMap,
-    hash::Hash,
-    sync::Arc,
-    time::{SystemTime, UNIX_EPOCH},
+    hash::{BuildHasher, Hash, RandomState},
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use color_eyre::{Result, eyre::bail};
-use tokio::sync::{Mutex, RwLock};
+use tokio::sync::Mutex;
 
 /// A simple ring buffer to maintain the last `CAPACITY` message IDs/users in a channel
 /// This is only used to keep a VERY small cache of the last few users in the channel
@@ -42,6 +45,12 @@ impl<const CAPACITY: usize> MessageBuffer<CAPACITY> {
         Some(self.data[self.ptr])
     }
 
+    /// Returns every message in the buffer, newest first
+    /// returns (`user id`, `message id`, `timestamp` (unix secs))
+    pub fn iter(&self) -> impl Iterator<Item = (u64, u64, u64)> + '_ {
+        (0..self.size).map(move |i| self.data[(self.ptr + CAPACITY - i) % CAPACITY])
+    }
+
     /// Appends a message to the ring buffer.
     pub fn push(&mut self, author_id: u64, msg_id: u64, timestamp: u64) {
         if self.size != 0 {
@@ -108,55 +117,201 @@ pub fn now() -> u64 {
         .as_secs()
 }
 
+/// Formats a number of seconds as e.g. `1h 2m 3s`
+pub fn format_duration(secs: u64) -> String {
+    let (h, m, s) = (secs / 3600, secs / 60 % 60, secs % 60);
+    match (h, m) {
+        (0, 0) => format!("{}s", s),
+        (0, _) => format!("{}m {}s", m, s),
+        _ => format!("{}h {}m {}s", h, m, s),
+    }
+}
+
 impl<const CAPACITY: usize> Default for MessageBuffer<CAPACITY> {
     fn default() -> Self {
         Self::new()
     }
 }
 
-/// A RwLock<HashMap<T, Arc<Mutex<U>>>> with some nice wrapper functions
+/// A HashMap<K, Arc<Mutex<V>>> split into shards that each have their own lock, holding at most
+/// `capacity` entries. When a shard is full the least recently used entry is evicted, and entries
+/// that haven't been used in a while can be swept with [`BoundedMap::evict_idle`].
 /// this isn't a super small data structure by any means but it's certainly smaller than enabling
 /// the entire Discord cache
-pub struct SyncMap<K, V>(RwLock<HashMap<K, Arc<Mutex<V>>>>);
+pub struct BoundedMap<K, V> {
+    shards: Vec<std::sync::Mutex<HashMap<K, Slot<V>>>>,
+    shard_capacity: usize,
+    hasher: RandomState,
+    evictions: AtomicU64,
+}
+
+struct Slot<V> {
+    value: Arc<Mutex<V>>,
+    last_used: Instant,
+}
 
-impl<K, V> SyncMap<K, V>
+impl<K, V> BoundedMap<K, V>
 where
     K: Eq + Hash + Clone,
     V: Default,
 {
-    pub fn new() -> Self {
-        Self(RwLock::new(HashMap::new()))
+    const SHARDS: usize = 16;
+
+    /// The capacity is split evenly between the shards and enforced per shard, so keys that hash
+    /// unevenly start being evicted before the map holds `capacity` entries in total (with a
+    /// capacity of 1000, a busy shard starts evicting at 63).
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            shards: (0..Self::SHARDS)
+                .map(|_| std::sync::Mutex::new(HashMap::new()))
+                .collect(),
+            shard_capacity: capacity.div_ceil(Self::SHARDS).max(1),
+            hasher: RandomState::new(),
+            evictions: AtomicU64::new(0),
+        }
+    }
+
+    fn shard(&self, key: &K) -> &std::sync::Mutex<HashMap<K, Slot<V>>> {
+        &self.shards[self.hasher.hash_one(key) as usize % Self::SHARDS]
     }
 
-    /// uses a read lock on the map. only writes if the key does not yet exist.
+    /// Only locks the shard the key is in. Inserts a default value if the key does not yet exist,
+    /// evicting the least recently used entry in the shard if it is full.
     /// Returns the mutex wrapping the value. Intentionally doesn't lock to allow the user to
     /// decide the semantics of allat
-    pub async fn get_or_insert(&self, key: &K) -> Arc<Mutex<V>> {
-        let mut map = self.0.read().await;
-
-        let v = match map.get(key) {
-            Some(key) => key,
-            None => {
-                drop(map);
-                let mut map_write = self.0.write().await;
-                map_write.insert(key.clone(), Arc::new(Mutex::new(V::default())));
-                drop(map_write);
-                map = self.0.read().await;
-                map.get(key).unwrap()
+    pub fn get_or_insert(&self, key: &K) -> Arc<Mutex<V>> {
+        let mut shard = self.shard(key).lock().unwrap();
+        let now = Instant::now();
+
+        if let Some(slot) = shard.get_mut(key) {
+            slot.last_used = now;
+            return slot.value.clone();
+        }
+
+        if shard.len() >= self.shard_capacity {
+            let oldest = shard
+                .iter()
+                .min_by_key(|(_, slot)| slot.last_used)
+                .map(|(k, _)| k.clone());
+            if let Some(oldest) = oldest {
+                shard.remove(&oldest);
+                self.evictions.fetch_add(1, Ordering::Relaxed);
             }
-        };
+        }
+
+        let value = Arc::new(Mutex::new(V::default()));
+        shard.insert(
+            key.clone(),
+            Slot {
+                value: value.clone(),
+                last_used: now,
+            },
+        );
+        value
+    }
 
-        v.clone()
+    pub fn get(&self, key: &K) -> Option<Arc<Mutex<V>>> {
+        let mut shard = self.shard(key).lock().unwrap();
+        let slot = shard.get_mut(key)?;
+        slot.last_used = Instant::now();
+        Some(slot.value.clone())
     }
 
-    pub async fn set(&self, key: &K, value: V) {
-        let v = self.get_or_insert(key).await;
-        let mut v = v.lock().await;
-        *v = value;
+    /// Removes every entry that hasn't been used for `max_idle`, returning how many were removed
+    pub fn evict_idle(&self, max_idle: Duration) -> usize {
+        let mut evicted = 0;
+        for shard in &self.shards {
+            let mut shard = shard.lock().unwrap();
+            let before = shard.len();
+            shard.retain(|_, slot| slot.last_used.elapsed() < max_idle);
+            evicted += before - shard.len();
+        }
+        self.evictions.fetch_add(evicted as u64, Ordering::Relaxed);
+        evicted
     }
 
-    pub async fn get(&self, key: &K) -> Option<Arc<Mutex<V>>> {
-        let map = self.0.read().await;
-        map.get(key).cloned()
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|s| s.lock().unwrap().len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.shards.iter().all(|s| s.lock().unwrap().is_empty())
+    }
+
+    /// Total number of entries evicted since the map was created
+    pub fn evictions(&self) -> u64 {
+        self.evictions.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Keys that all land in the same shard as `0`
+    fn same_shard(map: &BoundedMap<u64, u32>, n: usize) -> Vec<u64> {
+        let shard = map.shard(&0);
+        (0..)
+            .filter(|k| std::ptr::eq(map.shard(k), shard))
+            .take(n)
+            .collect()
+    }
+
+    #[test]
+    fn values_are_shared() {
+        let map = BoundedMap::<u64, u32>::new(16);
+        *map.get_or_insert(&1).try_lock().unwrap() = 5;
+
+        assert_eq!(*map.get_or_insert(&1).try_lock().unwrap(), 5);
+        assert_eq!(*map.get(&1).unwrap().try_lock().unwrap(), 5);
+        assert!(map.get(&2).is_none());
+    }
+
+    #[test]
+    fn never_holds_more_than_capacity() {
+        let map = BoundedMap::<u64, u32>::new(32);
+        for k in 0..1000 {
+            map.get_or_insert(&k);
+        }
+
+        assert!(map.len() <= 32);
+        assert_eq!(map.evictions(), 1000 - map.len() as u64);
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        // two entries per shard
+        let map = BoundedMap::<u64, u32>::new(32);
+        let keys = same_shard(&map, 3);
+
+        map.get_or_insert(&keys[0]);
+        std::thread::sleep(Duration::from_millis(1));
+        map.get_or_insert(&keys[1]);
+        std::thread::sleep(Duration::from_millis(1));
+        // using the first key again makes the second the oldest
+        map.get(&keys[0]);
+        std::thread::sleep(Duration::from_millis(1));
+        map.get_or_insert(&keys[2]);
+
+        assert!(map.get(&keys[0]).is_some());
+        assert!(map.get(&keys[1]).is_none());
+        assert!(map.get(&keys[2]).is_some());
+        assert_eq!(map.evictions(), 1);
+    }
+
+    #[test]
+    fn evicts_idle_entries() {
+        // room for all 10 keys even if they all hash into the same shard
+        let map = BoundedMap::<u64, u32>::new(16 * 10);
+        for k in 0..10 {
+            map.get_or_insert(&k);
+        }
+
+        assert_eq!(map.evict_idle(Duration::from_secs(60)), 0);
+        assert_eq!(map.len(), 10);
+
+        assert_eq!(map.evict_idle(Duration::ZERO), 10);
+        assert!(map.is_empty());
+        assert_eq!(map.evictions(), 10);
     }
 }
@@ -0,0 +1,54 @@
+use std::sync::Arc;
+
+use color_eyre::eyre::Error;
+use sqlx::SqlitePool;
+
+#[macro_use]
+extern crate tracing;
+
+pub mod announcements;
+pub mod channels;
+pub mod commands;
+pub mod config;
+pub mod game;
+pub mod handlers;
+pub mod helpers;
+pub mod models;
+pub mod notifications;
+pub mod outbox;
+pub mod participation;
+pub mod permissions;
+pub mod players;
+pub mod privacy;
+pub mod rebuild;
+pub mod revert;
+pub mod snapshots;
+pub mod stats;
+#[cfg(test)]
+mod test_support;
+pub mod treatments;
+pub mod vaccination;
+pub mod voice;
+
+pub struct Data {
+    pub started_at: u64,
+    /// map of channel IDs to the last few users to message there
+    pub channels: Arc<game::Channels>,
+    /// which channels the game runs in, and their multipliers
+    pub channel_rules: channels::ChannelRules,
+    /// messages per channel since the last snapshot
+    pub channel_activity: Arc<snapshots::ChannelActivity>,
+    pub voice: Arc<voice::Occupancy>,
+    pub game_config: config::GameConfig,
+    /// read-only view of every player - changes go through `game`
+    pub players: Arc<players::PlayerStore>,
+    /// who is playing - changes go through `game`
+    pub participation: Arc<participation::Participation>,
+    /// sends events to the actor that applies every game state transition
+    pub game: game::GameHandle,
+    /// wakes the worker that applies queued role changes
+    pub outbox: outbox::Outbox,
+    pub db_pool: SqlitePool,
+}
+
+pub type Context<'a> = poise::Context<'a, Data, Error>;
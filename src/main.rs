@@ -1,7 +1,14 @@
-use std::path::Path;
+use std::{
+    path::Path,
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
 
 use color_eyre::{Result, eyre::Error};
-use helpers::{MessageBuffer, SyncMap};
+use patient_zero::{
+    Data, announcements, channels, commands, config, game, handlers, helpers, notifications,
+    outbox, participation, permissions, players, rebuild, snapshots, voice,
+};
 use poise::serenity_prelude as serenity;
 use serenity::GatewayIntents;
 use sqlx::SqlitePool;
@@ -10,21 +17,30 @@ use tracing_subscriber::{EnvFilter, prelude::*};
 #[macro_use]
 extern crate tracing;
 
-mod commands;
-mod config;
-mod handlers;
-mod helpers;
-mod models;
-
-struct Data {
-    started_at: u64,
-    /// map of channel IDs to the ID of the last user to message there
-    channels: SyncMap<u64, MessageBuffer<10>>,
-    game_config: config::GameConfig,
-    db_pool: SqlitePool,
-}
+// the names of every command function, generated by build.rs
+include!(concat!(env!("OUT_DIR"), "/command_functions.rs"));
+
+/// Panics if a function in `commands` was never added to the framework, since it would silently
+/// be unusable
+fn assert_all_registered(commands: &[poise::Command<Data, Error>]) {
+    fn names<'a>(commands: &'a [poise::Command<Data, Error>], out: &mut Vec<&'a str>) {
+        for c in commands {
+            out.push(&c.identifying_name);
+            names(&c.subcommands, out);
+        }
+    }
+
+    let mut registered = Vec::new();
+    names(commands, &mut registered);
 
-type Context<'a> = poise::Context<'a, Data, Error>;
+    for f in COMMAND_FUNCTIONS {
+        assert!(
+            registered.contains(f),
+            "commands::{} is not registered in FrameworkOptions::commands",
+            f
+        );
+    }
+}
 
 async fn event_handler(
     ctx: &serenity::Context,
@@ -47,12 +63,77 @@ async fn event_handler(
         serenity::FullEvent::Message { new_message } => {
             handlers::new_message(ctx, data, new_message).await?
         }
+        serenity::FullEvent::ReactionAdd { add_reaction } => {
+            handlers::reaction_add(ctx, data, add_reaction).await?
+        }
+        serenity::FullEvent::VoiceStateUpdate { new, .. } => {
+            handlers::voice_state(ctx, data, new).await?
+        }
+        serenity::FullEvent::GuildCreate { guild, .. } => {
+            // whoever was already in voice channels when the bot connected. these voice states
+            // don't include the member, but the guild's members always include anyone in voice
+            for state in guild.voice_states.values() {
+                let mut state = state.clone();
+                if state.member.is_none() {
+                    state.member = guild.members.get(&state.user_id).cloned();
+                }
+                handlers::voice_state(ctx, data, &state).await?
+            }
+        }
         _ => (),
     }
 
     Ok(())
 }
 
+/// Periodically drops the buffers of channels nobody has spoken in for `max_idle`, so archived
+/// threads don't stick around forever
+fn spawn_channel_sweeper(channels: Arc<game::Channels>, max_idle: Duration) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(5 * 60));
+        loop {
+            interval.tick().await;
+            let evicted = channels.evict_idle(max_idle);
+            debug!(
+                "tracking {} channels, evicted {} idle ({} total evictions)",
+                channels.len(),
+                evicted,
+                channels.evictions()
+            );
+        }
+    });
+}
+
+/// Waits for ctrl-c, or SIGTERM from whatever is supervising the bot
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{SignalKind, signal};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(e) => {
+                error!("Failed to listen for SIGTERM: {:?}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => {
+            if let Err(e) = result {
+                error!("Failed to listen for ctrl-c: {:?}", e);
+                // SIGTERM can still stop the bot
+                std::future::pending::<()>().await;
+            }
+        }
+        _ = terminate => (),
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Error> {
     color_eyre::install()?;
@@ -71,26 +152,149 @@ async fn main() -> Result<(), Error> {
     let pool = SqlitePool::connect(&config.bot.db_url).await?;
     sqlx::migrate!().run(&pool).await?;
 
+    // `patient_zero rebuild [--repair]` checks the players table without starting the bot
+    let mut args = std::env::args().skip(1);
+    if args.next().as_deref() == Some("rebuild") {
+        let repair = args.any(|a| a == "--repair");
+        let discrepancies = rebuild::rebuild(&pool, repair, config.game.infected_role).await?;
+        for d in &discrepancies {
+            println!("{}", rebuild::describe(d));
+        }
+        println!(
+            "{} discrepancies found{}",
+            discrepancies.len(),
+            if repair {
+                " and repaired - roles will be updated the next time the bot starts"
+            } else {
+                ""
+            }
+        );
+        return Ok(());
+    }
+
+    let players = Arc::new(
+        players::PlayerStore::load(&pool, config.bot.flush_batch_size.unwrap_or(100)).await?,
+    );
+    players.spawn_flusher(
+        pool.clone(),
+        Duration::from_secs(config.bot.flush_interval.unwrap_or(5)),
+    );
+
+    let participation = Arc::new(participation::Participation::load(&pool, &config.game).await?);
+
     let intents = GatewayIntents::GUILD_MESSAGES
         | GatewayIntents::DIRECT_MESSAGES
         | GatewayIntents::GUILD_MEMBERS
-        | GatewayIntents::GUILD_MODERATION;
+        | GatewayIntents::GUILD_MODERATION
+        | GatewayIntents::GUILD_MESSAGE_REACTIONS
+        | GatewayIntents::GUILD_VOICE_STATES;
+
+    let channel_capacity = config.bot.channel_capacity.unwrap_or(1000);
+    let channels = Arc::new(game::Channels::new(channel_capacity));
+    let channel_rules = channels::ChannelRules::new(config.game.channels.clone(), channel_capacity);
+    let voice = Arc::new(voice::Occupancy::default());
+    spawn_channel_sweeper(
+        channels.clone(),
+        Duration::from_secs(config.bot.channel_idle_timeout.unwrap_or(24 * 60 * 60)),
+    );
+
+    // kept to flush counters once the client stops
+    let (exit_players, exit_pool) = (players.clone(), pool.clone());
+    let exit_game = Arc::new(OnceLock::<game::GameHandle>::new());
+    let setup_game = exit_game.clone();
+
+    let options = poise::FrameworkOptions {
+        commands: vec![
+            commands::ping(),
+            commands::infect(),
+            commands::cure(),
+            commands::infect_bulk(),
+            commands::infect_user(),
+            commands::cure_user(),
+            commands::user_status(),
+            commands::notifications(),
+            commands::participation(),
+            commands::join(),
+            commands::mydata(),
+            commands::treat(),
+            commands::vaccinate(),
+            commands::who_infected(),
+            commands::trace_infection(),
+            commands::stats(),
+            commands::undo(),
+            commands::rollback(),
+            commands::rebuild(),
+            commands::outbox(),
+            commands::admin(),
+        ],
+        command_check: Some(|ctx| Box::pin(permissions::check(ctx))),
+        event_handler: |ctx, event, framework, data| {
+            Box::pin(event_handler(ctx, event, framework, data))
+        },
+        ..Default::default()
+    };
+    assert_all_registered(&options.commands);
 
     let framework = poise::Framework::<Data, Error>::builder()
-        .options(poise::FrameworkOptions {
-            commands: vec![commands::ping(), commands::infect()],
-            event_handler: |ctx, event, framework, data| {
-                Box::pin(event_handler(ctx, event, framework, data))
-            },
-            ..Default::default()
-        })
+        .options(options)
         .setup(|ctx, _ready, framework| {
             Box::pin(async move {
-                poise::builtins::register_globally(ctx, &framework.options().commands).await?;
+                // registering in the guild updates instantly, unlike global commands, which
+                // are cleared in case an older version registered them
+                let guild_id = serenity::GuildId::new(config.game.server_id);
+                poise::builtins::register_in_guild(ctx, &framework.options().commands, guild_id)
+                    .await?;
+                serenity::Command::set_global_commands(ctx, vec![]).await?;
+
+                let outbox = outbox::Outbox::default();
+                outbox.spawn(ctx.http.clone(), pool.clone(), guild_id);
+
+                let announcer = announcements::Announcer::spawn(
+                    ctx.http.clone(),
+                    config.game.announcements.clone(),
+                );
+
+                let notifier = notifications::Notifier::spawn(
+                    ctx.http.clone(),
+                    pool.clone(),
+                    config.game.cure_threshold,
+                );
+
+                let game = game::Game {
+                    config: config.game.clone(),
+                    players: players.clone(),
+                    channels: channels.clone(),
+                    outbox: outbox.clone(),
+                    announcer,
+                    notifier,
+                    participation: participation.clone(),
+                    voice: voice.clone(),
+                    pool: pool.clone(),
+                }
+                .spawn();
+                let _ = setup_game.set(game.clone());
+
+                let channel_activity = Arc::new(snapshots::ChannelActivity::default());
+                if let Some(interval) = config.game.snapshot_interval {
+                    snapshots::spawn(
+                        pool.clone(),
+                        channel_activity.clone(),
+                        interval,
+                        config.game.snapshot_retention,
+                    );
+                }
+
                 Ok(Data {
                     started_at: helpers::now(),
                     game_config: config.game,
-                    channels: SyncMap::new(),
+                    players,
+                    participation,
+                    game,
+                    channels,
+                    channel_rules,
+                    channel_activity,
+                    voice,
+                    outbox,
                     db_pool: pool,
                 })
             })
@@ -102,5 +306,21 @@ async fn main() -> Result<(), Error> {
         .await
         .unwrap();
 
-    Ok(client.start().await?)
+    let shard_manager = client.shard_manager.clone();
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        info!("Shutting down");
+        shard_manager.shutdown_all().await;
+    });
+
+    let result = client.start().await;
+
+    // stop the game first so nothing is counted after the flush
+    if let Some(game) = exit_game.get() {
+        game.shutdown().await;
+    }
+    // anything counted since the last flush would otherwise be lost, even if the client failed
+    exit_players.flush(&exit_pool).await?;
+
+    Ok(result?)
 }
@@ -1,5 +1,5 @@
 use color_eyre::Result;
-use sqlx::SqliteExecutor;
+use sqlx::{SqliteExecutor, SqlitePool};
 
 pub struct Player {
     // this is disgusting - converting to a string is gross but unfortunately
@@ -9,6 +9,21 @@ pub struct Player {
     pub total_messages: i64,
     pub sanitized_messages: i64,
     pub last_action: i64,
+    pub cure_progress: i64,
+}
+
+impl Player {
+    /// Inserts an empty row for the player if they don't have one yet, e.g. so a moderator can be
+    /// recorded as the source of an infection
+    pub async fn create_if_missing(id: &str, e: impl SqliteExecutor<'_>) -> Result<()> {
+        sqlx::query!(
+            "INSERT INTO players (id) VALUES (?) ON CONFLICT (id) DO NOTHING",
+            id
+        )
+        .execute(e)
+        .await?;
+        Ok(())
+    }
 }
 
 #[derive(sqlx::Type)]
@@ -16,18 +31,59 @@ pub struct Player {
 pub enum InfectionEvent {
     Infected,
     Cured,
+    /// Doesn't change whether the player is infected
+    Vaccinated,
 }
 
 impl From<String> for InfectionEvent {
     fn from(value: String) -> Self {
         match value.as_str() {
             "cured" => Self::Cured,
+            "vaccinated" => Self::Vaccinated,
             _ => Self::Infected,
         }
     }
 }
 
+/// How an infection spread from one player to another
+#[derive(sqlx::Type, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[sqlx(rename_all = "lowercase")]
+pub enum TransmissionVector {
+    /// Sent the message after an infected player's
+    Proximity,
+    /// Replied to an infected player's message
+    Reply,
+    /// Mentioned an infected player
+    Mention,
+    /// Was mentioned by an infected player
+    Mentioned,
+    /// Reacted to an infected player's message
+    Reaction,
+    /// Spent time in a voice channel with an infected player
+    Voice,
+    /// Treated an infected player as a doctor
+    Treatment,
+}
+
+impl TransmissionVector {
+    /// Describes how the infection spread, e.g. `replying to` so it reads as "infected by
+    /// replying to @x"
+    pub fn describe(&self) -> &'static str {
+        match self {
+            Self::Proximity => "proximity to",
+            Self::Reply => "replying to",
+            Self::Mention => "mentioning",
+            Self::Mentioned => "being mentioned by",
+            Self::Reaction => "reacting to",
+            Self::Voice => "sharing a voice channel with",
+            Self::Treatment => "treating",
+        }
+    }
+}
+
 pub struct InfectionRecord {
+    /// assigned by the database, so ignored when saving
+    pub id: i64,
     pub event: InfectionEvent,
     pub target: String,
     pub source: Option<String>,
@@ -35,15 +91,24 @@ pub struct InfectionRecord {
     pub recorded_at: i64,
     pub target_total_messages: i64,
     pub target_sanitized_messages: i64,
+    /// the record this one reverts, for records written by `/undo` and `/rollback`
+    pub reverts: Option<i64>,
+    /// how the infection spread, for infections that spread from another player
+    pub vector: Option<TransmissionVector>,
 }
 
 impl InfectionRecord {
+    /// Whether the record infected its target
+    pub fn infects(&self) -> bool {
+        matches!(self.event, InfectionEvent::Infected)
+    }
+
     pub async fn save(self, e: impl SqliteExecutor<'_>) -> Result<()> {
         sqlx::query!(
             r#"
             INSERT INTO infection_records
-            (event, target, source, reason, recorded_at, target_total_messages, target_sanitized_messages) 
-            VALUES (?, ?, ?, ?, ?, ?, ?)
+            (event, target, source, reason, recorded_at, target_total_messages, target_sanitized_messages, reverts, vector)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)
             "#,
             self.event,
             self.target,
@@ -52,7 +117,93 @@ impl InfectionRecord {
             self.recorded_at,
             self.target_total_messages,
             self.target_sanitized_messages,
+            self.reverts,
+            self.vector,
         ).execute(e).await?;
         Ok(())
     }
+
+    /// Fetches the latest infection or cure for `target` at or before `before`
+    pub async fn latest_for(
+        target: &str,
+        before: i64,
+        e: impl SqliteExecutor<'_>,
+    ) -> Result<Option<Self>> {
+        Ok(sqlx::query_as!(
+            Self,
+            r#"
+            SELECT id, event, target, source, reason, recorded_at, target_total_messages, target_sanitized_messages, reverts,
+                vector AS "vector: TransmissionVector"
+            FROM infection_records WHERE target = ? AND recorded_at <= ? AND event != 'vaccinated'
+            ORDER BY recorded_at DESC, id DESC LIMIT 1
+            "#,
+            target,
+            before
+        )
+        .fetch_optional(e)
+        .await?)
+    }
+
+    /// Follows the chain of infections back from `target` as of `at`, returning the record that
+    /// infected each player in turn. The chain ends at a source that wasn't infected themselves,
+    /// e.g. a moderator.
+    pub async fn trace(target: &str, at: i64, pool: &SqlitePool) -> Result<Vec<Self>> {
+        // stops a corrupted table from looping forever
+        const MAX_DEPTH: usize = 50;
+
+        let mut chain: Vec<Self> = Vec::new();
+        let (mut target, mut at) = (target.to_string(), at);
+
+        while chain.len() < MAX_DEPTH {
+            let Some(record) = Self::latest_for(&target, at, pool).await? else {
+                break;
+            };
+            if !record.infects() || chain.iter().any(|r| r.target == record.target) {
+                break;
+            }
+
+            let next = record.source.clone();
+            at = record.recorded_at;
+            chain.push(record);
+
+            match next {
+                Some(source) => target = source,
+                None => break,
+            }
+        }
+
+        Ok(chain)
+    }
+
+    /// Fetches every record, oldest first
+    pub async fn all(e: impl SqliteExecutor<'_>) -> Result<Vec<Self>> {
+        Ok(sqlx::query_as!(
+            Self,
+            r#"
+            SELECT id, event, target, source, reason, recorded_at, target_total_messages, target_sanitized_messages, reverts,
+                vector AS "vector: TransmissionVector"
+            FROM infection_records ORDER BY recorded_at, id
+            "#
+        )
+        .fetch_all(e)
+        .await?)
+    }
+
+    /// Fetches every record that hasn't been reverted, oldest first. Records that revert others
+    /// are left out too, so this is the history as if the reverted records never happened.
+    pub async fn effective(e: impl SqliteExecutor<'_>) -> Result<Vec<Self>> {
+        Ok(sqlx::query_as!(
+            Self,
+            r#"
+            SELECT id, event, target, source, reason, recorded_at, target_total_messages, target_sanitized_messages, reverts,
+                vector AS "vector: TransmissionVector"
+            FROM infection_records r
+            WHERE reverts IS NULL
+                AND NOT EXISTS (SELECT 1 FROM infection_records c WHERE c.reverts = r.id)
+            ORDER BY recorded_at, id
+            "#
+        )
+        .fetch_all(e)
+        .await?)
+    }
 }
@@ -0,0 +1,206 @@
+//! Opt-in direct messages to players when their state changes. Players choose which events they
+//! get with `/notifications`, and nothing is sent until they do.
+
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use color_eyre::Result;
+use poise::serenity_prelude as serenity;
+use serenity::{CreateMessage, Http, HttpError, UserId};
+use sqlx::{SqliteExecutor, SqlitePool};
+use tokio::{sync::mpsc, time::Instant};
+
+use crate::{helpers, models::Player};
+
+/// How many notifications can be waiting to be sent before new ones are dropped
+const QUEUE_SIZE: usize = 1024;
+/// Minimum time between exposure notifications for one player, since every message after an
+/// infected player's counts as an exposure while they're on cooldown
+const EXPOSED_COOLDOWN: Duration = Duration::from_secs(60 * 60);
+/// Discord's error code for users that don't accept DMs from the bot
+const CANNOT_MESSAGE_USER: isize = 50007;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NotificationSettings {
+    pub infected: bool,
+    pub exposed: bool,
+    pub nearing_cure: bool,
+    pub cured: bool,
+    /// when Discord last refused to deliver a DM to the player
+    pub dms_closed_at: Option<i64>,
+}
+
+impl NotificationSettings {
+    /// Fetches the player's settings, which are all off if they've never changed them
+    pub async fn get(player: &str, e: impl SqliteExecutor<'_>) -> Result<Self> {
+        Ok(sqlx::query_as!(
+            Self,
+            r#"
+            SELECT infected, exposed, nearing_cure, cured, dms_closed_at
+            FROM notification_settings WHERE player = ?
+            "#,
+            player
+        )
+        .fetch_optional(e)
+        .await?
+        .unwrap_or_default())
+    }
+
+    /// Saves the settings, clearing `dms_closed_at` so DMs are tried again
+    pub async fn save(&self, player: &str, pool: &SqlitePool) -> Result<()> {
+        let mut tx = pool.begin().await?;
+        Player::create_if_missing(player, &mut *tx).await?;
+        sqlx::query!(
+            r#"
+            INSERT INTO notification_settings (player, infected, exposed, nearing_cure, cured)
+            VALUES (?, ?, ?, ?, ?)
+            ON CONFLICT (player) DO UPDATE SET
+                infected = excluded.infected,
+                exposed = excluded.exposed,
+                nearing_cure = excluded.nearing_cure,
+                cured = excluded.cured,
+                dms_closed_at = NULL
+            "#,
+            player,
+            self.infected,
+            self.exposed,
+            self.nearing_cure,
+            self.cured,
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    fn wants(&self, notification: &Notification) -> bool {
+        match notification {
+            Notification::Infected { .. } => self.infected,
+            Notification::Exposed { .. } => self.exposed,
+            Notification::NearingCure { .. } => self.nearing_cure,
+            Notification::Cured => self.cured,
+        }
+    }
+}
+
+pub enum Notification {
+    /// `channel` is the text channel they were infected in, if it was by a message or reaction
+    Infected {
+        channel: Option<u64>,
+    },
+    /// Spoke right after an infected player who couldn't infect anyone yet
+    Exposed {
+        channel: u64,
+    },
+    NearingCure {
+        remaining: i64,
+    },
+    Cured,
+}
+
+impl Notification {
+    fn message(&self, cure_threshold: u32) -> String {
+        match self {
+            Self::Infected { channel } => format!(
+                "You've been infected{}! Send {} more messages to recover.",
+                channel.map_or(String::new(), |c| format!(" in <#{}>", c)),
+                // players are cured once they've sent more than the threshold
+                cure_threshold + 1
+            ),
+            Self::Exposed { channel } => format!(
+                "You were exposed to an infected player in <#{}>, but didn't catch it this time.",
+                channel
+            ),
+            Self::NearingCure { remaining } => {
+                format!("You're almost better - {} more messages to go.", remaining)
+            }
+            Self::Cured => "You've recovered and are no longer infected.".to_string(),
+        }
+    }
+}
+
+/// Queues notifications for the worker that sends them
+#[derive(Clone)]
+pub struct Notifier(mpsc::Sender<(u64, Notification)>);
+
+impl Notifier {
+    /// Spawns the worker that sends notifications
+    pub fn spawn(http: Arc<Http>, pool: SqlitePool, cure_threshold: u32) -> Self {
+        let (tx, rx) = mpsc::channel(QUEUE_SIZE);
+        tokio::spawn(run(http, pool, cure_threshold, rx));
+        Self(tx)
+    }
+
+    /// Queues a notification for the player without waiting for it to be sent. It's dropped
+    /// later if they haven't opted in to it.
+    pub fn notify(&self, player: u64, notification: Notification) {
+        // the game actor shouldn't wait on Discord
+        if self.0.try_send((player, notification)).is_err() {
+            warn!("Notification queue is full, dropping notification");
+        }
+    }
+}
+
+async fn run(
+    http: Arc<Http>,
+    pool: SqlitePool,
+    cure_threshold: u32,
+    mut rx: mpsc::Receiver<(u64, Notification)>,
+) {
+    let mut last_exposed: HashMap<u64, Instant> = HashMap::new();
+
+    while let Some((player, notification)) = rx.recv().await {
+        if let Notification::Exposed { .. } = notification {
+            let now = Instant::now();
+            if last_exposed
+                .get(&player)
+                .is_some_and(|t| now - *t < EXPOSED_COOLDOWN)
+            {
+                continue;
+            }
+            last_exposed.insert(player, now);
+            last_exposed.retain(|_, t| now - *t < EXPOSED_COOLDOWN);
+        }
+
+        if let Err(e) = send(&http, &pool, player, &notification, cure_threshold).await {
+            error!("Failed to notify player {}: {:?}", player, e);
+        }
+    }
+}
+
+async fn send(
+    http: &Http,
+    pool: &SqlitePool,
+    player: u64,
+    notification: &Notification,
+    cure_threshold: u32,
+) -> Result<()> {
+    let id = player.to_string();
+    let settings = NotificationSettings::get(&id, pool).await?;
+    if !settings.wants(notification) || settings.dms_closed_at.is_some() {
+        return Ok(());
+    }
+
+    let message = CreateMessage::new().content(notification.message(cure_threshold));
+    match UserId::new(player).direct_message(http, message).await {
+        Ok(_) => Ok(()),
+        Err(serenity::Error::Http(HttpError::UnsuccessfulRequest(e)))
+            if e.error.code == CANNOT_MESSAGE_USER =>
+        {
+            // stop trying until they change their settings, which they'd do after opening DMs
+            info!(
+                "Player {} doesn't accept DMs, pausing notifications",
+                player
+            );
+            let now = helpers::now() as i64;
+            sqlx::query!(
+                "UPDATE notification_settings SET dms_closed_at = ? WHERE player = ?",
+                now,
+                id
+            )
+            .execute(pool)
+            .await?;
+            Ok(())
+        }
+        Err(e) => Err(e.into()),
+    }
+}
@@ -0,0 +1,295 @@
+//! Discord role changes are written to the `role_changes` outbox in the same transaction as the
+//! state change that caused them, then applied by a single background worker. Anything that fails
+//! is retried with backoff, so a Discord outage or a mass outbreak can't leave roles out of sync
+//! with the database.
+
+use std::{sync::Arc, time::Duration};
+
+use color_eyre::Result;
+use poise::serenity_prelude as serenity;
+use serenity::{GuildId, Http, RoleId, UserId};
+use sqlx::{SqliteExecutor, SqlitePool};
+use tokio::sync::Notify;
+
+use crate::helpers;
+
+/// How often the worker checks for changes that are due a retry, even if it isn't woken
+const POLL_INTERVAL: Duration = Duration::from_secs(30);
+/// Minimum time between requests to Discord, on top of serenity's own rate limiting, so a large
+/// outbreak doesn't starve every other request in the bucket
+const REQUEST_INTERVAL: Duration = Duration::from_millis(250);
+/// Number of attempts before a change is marked as permanently failed
+const MAX_ATTEMPTS: i64 = 8;
+/// Delay before the first retry, doubled for every attempt after (seconds)
+const BASE_BACKOFF: i64 = 5;
+const MAX_BACKOFF: i64 = 60 * 60;
+
+#[derive(sqlx::Type, Clone, Copy, Debug)]
+#[sqlx(rename_all = "lowercase")]
+pub enum RoleAction {
+    Add,
+    Remove,
+}
+
+impl From<String> for RoleAction {
+    fn from(value: String) -> Self {
+        match value.as_str() {
+            "remove" => Self::Remove,
+            _ => Self::Add,
+        }
+    }
+}
+
+pub struct RoleChange {
+    pub member: String,
+    pub role: String,
+    pub action: RoleAction,
+}
+
+impl RoleChange {
+    pub fn new(member: impl ToString, role: u64, action: RoleAction) -> Self {
+        Self {
+            member: member.to_string(),
+            role: role.to_string(),
+            action,
+        }
+    }
+
+    /// Adds the change to the outbox. It isn't applied until the worker is woken with
+    /// [`Outbox::wake`] or next polls.
+    pub async fn enqueue(self, e: impl SqliteExecutor<'_>) -> Result<()> {
+        sqlx::query!(
+            "INSERT INTO role_changes (member, role, action) VALUES (?, ?, ?)",
+            self.member,
+            self.role,
+            self.action,
+        )
+        .execute(e)
+        .await?;
+        Ok(())
+    }
+}
+
+/// A role change that won't be retried
+pub struct FailedRoleChange {
+    pub id: i64,
+    pub member: String,
+    pub action: RoleAction,
+    pub attempts: i64,
+    pub last_error: Option<String>,
+    pub failed_at: Option<i64>,
+}
+
+impl FailedRoleChange {
+    pub async fn all(e: impl SqliteExecutor<'_>) -> Result<Vec<Self>> {
+        Ok(sqlx::query_as!(
+            Self,
+            r#"
+            SELECT id, member, action, attempts, last_error, failed_at FROM role_changes
+            WHERE failed_at IS NOT NULL AND completed_at IS NULL ORDER BY id
+            "#
+        )
+        .fetch_all(e)
+        .await?)
+    }
+
+    /// Moves every failed change back into the queue, returning how many there were. Changes
+    /// that a newer change for the same member and role has superseded are marked as completed
+    /// instead, since applying them now would undo the newer one.
+    pub async fn retry_all(pool: &SqlitePool) -> Result<u64> {
+        let now = helpers::now() as i64;
+        let mut tx = pool.begin().await?;
+
+        sqlx::query!(
+            r#"
+            UPDATE role_changes SET completed_at = ?
+            WHERE failed_at IS NOT NULL AND completed_at IS NULL AND EXISTS (
+                SELECT 1 FROM role_changes newer
+                WHERE newer.member = role_changes.member
+                    AND newer.role = role_changes.role
+                    AND newer.id > role_changes.id
+            )
+            "#,
+            now
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        let retried = sqlx::query!(
+            r#"
+            UPDATE role_changes SET failed_at = NULL, attempts = 0, next_attempt_at = 0
+            WHERE failed_at IS NOT NULL AND completed_at IS NULL
+            "#
+        )
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+
+        tx.commit().await?;
+        Ok(retried)
+    }
+}
+
+/// Handle used to wake the outbox worker after queueing changes
+#[derive(Clone, Default)]
+pub struct Outbox(Arc<Notify>);
+
+impl Outbox {
+    pub fn wake(&self) {
+        self.0.notify_one();
+    }
+
+    /// Spawns the worker that applies queued role changes
+    pub fn spawn(&self, http: Arc<Http>, pool: SqlitePool, guild_id: GuildId) {
+        let notify = self.0.clone();
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = process(&http, &pool, guild_id).await {
+                    error!("Failed to process role changes: {:?}", e);
+                }
+
+                tokio::select! {
+                    _ = notify.notified() => (),
+                    _ = tokio::time::sleep(POLL_INTERVAL) => (),
+                }
+            }
+        });
+    }
+}
+
+/// Only the newest pending change for a member and role matters - an add followed by a remove
+/// is just a remove - so older ones are marked as completed without being sent.
+async fn coalesce(pool: &SqlitePool) -> Result<()> {
+    let now = helpers::now() as i64;
+    let coalesced = sqlx::query!(
+        r#"
+        UPDATE role_changes SET completed_at = ?
+        WHERE completed_at IS NULL AND failed_at IS NULL AND EXISTS (
+            SELECT 1 FROM role_changes newer
+            WHERE newer.member = role_changes.member
+                AND newer.role = role_changes.role
+                AND newer.id > role_changes.id
+                AND newer.completed_at IS NULL
+                AND newer.failed_at IS NULL
+        )
+        "#,
+        now
+    )
+    .execute(pool)
+    .await?
+    .rows_affected();
+
+    if coalesced > 0 {
+        debug!("coalesced {} superseded role changes", coalesced);
+    }
+
+    Ok(())
+}
+
+/// Deletes the finished changes of members who have deleted their data. See [`privacy::delete`].
+///
+/// [`privacy::delete`]: crate::privacy::delete
+async fn forget(pool: &SqlitePool) -> Result<()> {
+    sqlx::query!(
+        r#"
+        DELETE FROM role_changes
+        WHERE forget AND (completed_at IS NOT NULL OR failed_at IS NOT NULL)
+        "#
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Applies every pending role change that is due, in the order they were made
+async fn process(http: &Http, pool: &SqlitePool, guild_id: GuildId) -> Result<()> {
+    coalesce(pool).await?;
+    forget(pool).await?;
+
+    let now = helpers::now() as i64;
+    let pending = sqlx::query!(
+        r#"
+        SELECT id, member, role, action AS "action: RoleAction", attempts FROM role_changes
+        WHERE completed_at IS NULL AND failed_at IS NULL AND next_attempt_at <= ?
+        ORDER BY id
+        "#,
+        now
+    )
+    .fetch_all(pool)
+    .await?;
+
+    for change in pending {
+        let (Ok(member), Ok(role)) = (change.member.parse(), change.role.parse()) else {
+            continue;
+        };
+        let (member, role) = (UserId::new(member), RoleId::new(role));
+
+        let result = match change.action {
+            RoleAction::Add => http.add_member_role(guild_id, member, role, None).await,
+            RoleAction::Remove => http.remove_member_role(guild_id, member, role, None).await,
+        };
+
+        let now = helpers::now() as i64;
+        match result {
+            Ok(()) => {
+                sqlx::query!(
+                    "UPDATE role_changes SET completed_at = ?, attempts = attempts + 1 WHERE id = ?",
+                    now,
+                    change.id
+                )
+                .execute(pool)
+                .await?;
+            }
+            Err(e) => {
+                let attempts = change.attempts + 1;
+                let error = e.to_string();
+
+                // client errors (e.g. the member left) will never succeed, unless we were just
+                // rate limited
+                let permanent = attempts >= MAX_ATTEMPTS
+                    || http_status(&e).is_some_and(|s| (400..500).contains(&s) && s != 429);
+
+                if permanent {
+                    error!(
+                        "Giving up on {:?} role for {} after {} attempts: {}",
+                        change.action, member, attempts, e
+                    );
+                } else {
+                    warn!(
+                        "Failed to {:?} role for {}, will retry: {}",
+                        change.action, member, e
+                    );
+                }
+
+                let backoff = (BASE_BACKOFF << (attempts - 1).min(20)).min(MAX_BACKOFF);
+                let next_attempt_at = now + backoff;
+                let failed_at = permanent.then_some(now);
+                sqlx::query!(
+                    r#"
+                    UPDATE role_changes
+                    SET attempts = ?, last_error = ?, next_attempt_at = ?, failed_at = ?
+                    WHERE id = ?
+                    "#,
+                    attempts,
+                    error,
+                    next_attempt_at,
+                    failed_at,
+                    change.id
+                )
+                .execute(pool)
+                .await?;
+            }
+        }
+
+        tokio::time::sleep(REQUEST_INTERVAL).await;
+    }
+
+    forget(pool).await
+}
+
+fn http_status(e: &serenity::Error) -> Option<u16> {
+    match e {
+        serenity::Error::Http(e) => e.status_code().map(|s| s.as_u16()),
+        _ => None,
+    }
+}
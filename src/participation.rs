@@ -0,0 +1,95 @@
+//! Who is playing. Members can opt out with `/participation`, and in opt-in games only members
+//! with the join role or who ran `/join` play. Messages from anyone else are ignored entirely.
+
+use std::{collections::HashMap, sync::RwLock};
+
+use color_eyre::Result;
+use poise::serenity_prelude as serenity;
+use serenity::RoleId;
+use sqlx::SqlitePool;
+
+use crate::{config::GameConfig, models::Player};
+
+#[derive(sqlx::Type, Clone, Copy, Debug, PartialEq, Eq)]
+#[sqlx(rename_all = "snake_case")]
+pub enum Status {
+    Joined,
+    OptedOut,
+}
+
+impl From<String> for Status {
+    fn from(value: String) -> Self {
+        match value.as_str() {
+            "joined" => Self::Joined,
+            _ => Self::OptedOut,
+        }
+    }
+}
+
+pub struct Participation {
+    /// only players who have run `/participation` or `/join` are in here
+    statuses: RwLock<HashMap<u64, Status>>,
+    opt_in: bool,
+    join_role: Option<RoleId>,
+}
+
+impl Participation {
+    pub async fn load(pool: &SqlitePool, config: &GameConfig) -> Result<Self> {
+        let statuses =
+            sqlx::query!(r#"SELECT player, status AS "status: Status" FROM participation"#)
+                .fetch_all(pool)
+                .await?
+                .into_iter()
+                .filter_map(|r| Some((r.player.parse().ok()?, r.status)))
+                .collect();
+
+        Ok(Self {
+            statuses: RwLock::new(statuses),
+            opt_in: config.opt_in,
+            join_role: config.join_role.map(RoleId::new),
+        })
+    }
+
+    /// Whether the player's messages count towards the game, given their roles
+    pub fn plays(&self, id: u64, roles: &[RoleId]) -> bool {
+        match self.statuses.read().unwrap().get(&id) {
+            Some(Status::Joined) => true,
+            Some(Status::OptedOut) => false,
+            None => !self.opt_in || self.join_role.is_some_and(|r| roles.contains(&r)),
+        }
+    }
+
+    /// Whether [`Self::plays`] depends on roles, so callers only fetch them when they're needed
+    pub fn uses_roles(&self) -> bool {
+        self.opt_in && self.join_role.is_some()
+    }
+
+    /// Opted out players can never be infected, even by moderators
+    pub fn opted_out(&self, id: u64) -> bool {
+        self.statuses.read().unwrap().get(&id) == Some(&Status::OptedOut)
+    }
+
+    /// Saves the player's status, applying it in memory once it has been written
+    pub async fn set(&self, id: u64, status: Status, pool: &SqlitePool) -> Result<()> {
+        let player = id.to_string();
+        let mut tx = pool.begin().await?;
+
+        Player::create_if_missing(&player, &mut *tx).await?;
+        sqlx::query!(
+            r#"
+            INSERT INTO participation (player, status) VALUES (?, ?)
+            ON CONFLICT (player) DO UPDATE SET
+                status = excluded.status,
+                changed_at = unixepoch()
+            "#,
+            player,
+            status,
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        self.statuses.write().unwrap().insert(id, status);
+        Ok(())
+    }
+}
@@ -0,0 +1,80 @@
+//! Decides who can run each command, based on `[game.permissions]` in the config.
+
+use color_eyre::Result;
+use poise::{CreateReply, serenity_prelude as serenity};
+use serenity::Permissions;
+
+/// Permissions needed for commands without an entry in the config
+const DEFAULT_PERMISSIONS: Permissions = Permissions::MANAGE_MESSAGES;
+/// The `category` of commands about a player's own settings, which anyone can run unless the
+/// config says otherwise. Every other command is also hidden in Discord from members without
+/// [`DEFAULT_PERMISSIONS`] through its `default_member_permissions`, which server admins can
+/// override in the integration settings
+const PLAYER_CATEGORY: &str = "Player";
+
+#[derive(serde::Deserialize, Clone, Default)]
+pub struct CommandPermissions {
+    /// Lets anyone run the command
+    #[serde(default)]
+    pub everyone: bool,
+    /// Roles that can run the command
+    pub roles: Option<Vec<u64>>,
+    /// Discord permissions that let a member run the command, e.g. `MANAGE_MESSAGES`
+    pub permissions: Option<Vec<String>>,
+}
+
+impl CommandPermissions {
+    fn allows(&self, command: &str, member: &serenity::Member) -> bool {
+        if self.everyone {
+            return true;
+        }
+
+        let roles = self.roles.as_deref().unwrap_or_default();
+        if member.roles.iter().any(|r| roles.contains(&r.get())) {
+            return true;
+        }
+
+        let member_permissions = member.permissions.unwrap_or_default();
+        self.permissions
+            .iter()
+            .flatten()
+            .any(|name| match Permissions::from_name(name) {
+                Some(p) => member_permissions.contains(p),
+                None => {
+                    warn!("Unknown permission {} configured for /{}", name, command);
+                    false
+                }
+            })
+    }
+}
+
+/// Used as the framework's `command_check`. Members that aren't allowed to run a command are told
+/// so privately.
+pub async fn check(ctx: crate::Context<'_>) -> Result<bool> {
+    let command = &ctx.command().qualified_name;
+
+    let Some(member) = ctx.author_member().await else {
+        // commands are only usable in the game's server
+        return Ok(false);
+    };
+
+    let allowed = match ctx.data().game_config.permissions.get(command) {
+        Some(p) => p.allows(command, &member),
+        None if ctx.command().category.as_deref() == Some(PLAYER_CATEGORY) => true,
+        None => member
+            .permissions
+            .unwrap_or_default()
+            .contains(DEFAULT_PERMISSIONS),
+    };
+
+    if !allowed {
+        ctx.send(
+            CreateReply::default()
+                .content("You don't have permission to use this command.")
+                .ephemeral(true),
+        )
+        .await?;
+    }
+
+    Ok(allowed)
+}
@@ -0,0 +1,364 @@
+//! The authoritative in-memory copy of every player's state. It's loaded from the database at
+//! startup so handling a message never has to wait on a read.
+//!
+//! Infections and cures are written through to the database immediately, as part of the same
+//! transaction as their infection record. Message counters change far too often for that, so
+//! they are only marked dirty and written in batches by [`PlayerStore::spawn_flusher`].
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use color_eyre::Result;
+use sqlx::{SqliteExecutor, SqlitePool};
+use tokio::sync::Notify;
+
+use crate::{
+    models::{Player, TransmissionVector},
+    vaccination::Vaccine,
+};
+
+#[derive(Clone, Debug, Default)]
+pub struct PlayerState {
+    pub infected: bool,
+    pub total_messages: i64,
+    pub sanitized_messages: i64,
+    /// timestamp of the last sanitized message
+    pub last_action: i64,
+    /// when the player was last infected
+    pub infected_at: Option<i64>,
+    /// `sanitized_messages` at the time the player was last infected
+    pub infected_sanitized_messages: i64,
+    /// how much the player has done towards curing since they were last infected. Unlike
+    /// `sanitized_messages`, this includes cure multipliers and treatments
+    pub cure_progress: i64,
+    /// when the player last infected someone else through each vector
+    pub last_transmissions: HashMap<TransmissionVector, i64>,
+    /// the player's latest vaccine, even if it's worn off
+    pub vaccine: Option<Vaccine>,
+}
+
+impl PlayerState {
+    /// Returns the state after the player is infected or cured. Infecting also resets the
+    /// cooldown on their messages so they don't immediately start a chain reaction.
+    pub fn with_infected(&self, infected: bool, now: i64) -> Self {
+        let mut state = self.clone();
+        state.infected = infected;
+        state.last_action = now;
+        state.cure_progress = 0;
+        if infected {
+            state.infected_at = Some(now);
+            state.infected_sanitized_messages = state.sanitized_messages;
+        }
+        state
+    }
+
+    /// Whether the player's vaccine protects them from `strain` at `now`
+    pub fn immune(&self, strain: Option<&str>, now: i64) -> bool {
+        self.vaccine
+            .as_ref()
+            .is_some_and(|v| v.protects(strain, now))
+    }
+
+    /// When the player last infected someone else through `vector`
+    pub fn last_transmission(&self, vector: TransmissionVector) -> Option<i64> {
+        self.last_transmissions.get(&vector).copied()
+    }
+
+    /// Writes the whole player row, including the counters
+    pub async fn save(&self, id: u64, e: impl SqliteExecutor<'_>) -> Result<()> {
+        let id = id.to_string();
+        sqlx::query!(
+            r#"
+            INSERT INTO players
+                (id, infected, total_messages, sanitized_messages, last_action, cure_progress)
+            VALUES (?, ?, ?, ?, ?, ?)
+            ON CONFLICT (id) DO UPDATE SET
+                infected = excluded.infected,
+                total_messages = excluded.total_messages,
+                sanitized_messages = excluded.sanitized_messages,
+                last_action = excluded.last_action,
+                cure_progress = excluded.cure_progress
+            "#,
+            id,
+            self.infected,
+            self.total_messages,
+            self.sanitized_messages,
+            self.last_action,
+            self.cure_progress,
+        )
+        .execute(e)
+        .await?;
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct Players {
+    states: HashMap<u64, PlayerState>,
+    /// players whose counters have changed since the last flush
+    dirty: HashSet<u64>,
+}
+
+pub struct PlayerStore {
+    players: RwLock<Players>,
+    /// number of dirty players that triggers a flush before the interval is up
+    batch_size: usize,
+    flush: Notify,
+}
+
+impl PlayerStore {
+    /// Loads every player, along with the parts of their state that only live in the infection
+    /// records.
+    pub async fn load(pool: &SqlitePool, batch_size: usize) -> Result<Self> {
+        let mut players: HashMap<u64, PlayerState> =
+            sqlx::query_as!(Player, "SELECT * FROM players")
+                .fetch_all(pool)
+                .await?
+                .into_iter()
+                .filter_map(|p| {
+                    let state = PlayerState {
+                        infected: p.infected,
+                        total_messages: p.total_messages,
+                        sanitized_messages: p.sanitized_messages,
+                        last_action: p.last_action,
+                        cure_progress: p.cure_progress,
+                        ..Default::default()
+                    };
+                    Some((p.id.parse().ok()?, state))
+                })
+                .collect();
+
+        // rows are ordered, so the last one seen for each player is their latest infection
+        let infections = sqlx::query!(
+            r#"
+            SELECT target, recorded_at, target_sanitized_messages FROM infection_records
+            WHERE event = 'infected' ORDER BY recorded_at, id
+            "#
+        )
+        .fetch_all(pool)
+        .await?;
+
+        for r in infections {
+            if let Some(p) = r.target.parse().ok().and_then(|id| players.get_mut(&id)) {
+                p.infected_at = Some(r.recorded_at);
+                p.infected_sanitized_messages = r.target_sanitized_messages;
+            }
+        }
+
+        // infections without a vector, e.g. by moderators, have always counted as proximity
+        let transmissions = sqlx::query!(
+            r#"
+            SELECT source AS "source!", COALESCE(vector, 'proximity') AS "vector!: TransmissionVector",
+                MAX(recorded_at) AS "recorded_at!: i64"
+            FROM infection_records WHERE event = 'infected' AND source IS NOT NULL
+            GROUP BY source, COALESCE(vector, 'proximity')
+            "#
+        )
+        .fetch_all(pool)
+        .await?;
+
+        for r in transmissions {
+            if let Some(p) = r.source.parse().ok().and_then(|id| players.get_mut(&id)) {
+                p.last_transmissions.insert(r.vector, r.recorded_at);
+            }
+        }
+
+        for (id, vaccine) in Vaccine::all(pool).await? {
+            if let Some(p) = id.parse().ok().and_then(|id| players.get_mut(&id)) {
+                p.vaccine = Some(vaccine);
+            }
+        }
+
+        info!("Loaded {} players", players.len());
+
+        Ok(Self {
+            players: RwLock::new(Players {
+                states: players,
+                dirty: HashSet::new(),
+            }),
+            batch_size,
+            flush: Notify::new(),
+        })
+    }
+
+    pub fn get(&self, id: u64) -> Option<PlayerState> {
+        self.players.read().unwrap().states.get(&id).cloned()
+    }
+
+    /// Returns the ids of every player matching `f`
+    pub fn ids_where(&self, f: impl Fn(&PlayerState) -> bool) -> Vec<u64> {
+        self.players
+            .read()
+            .unwrap()
+            .states
+            .iter()
+            .filter(|(_, p)| f(p))
+            .map(|(id, _)| *id)
+            .collect()
+    }
+
+    /// Counts a message from the player, only counting it as sanitized if `cooldown` seconds have
+    /// passed since the last one that was. Sanitized messages from infected players count as
+    /// `credit` messages towards curing. Returns the player's new state and how much it counted
+    /// towards curing.
+    pub fn record_message(
+        &self,
+        id: u64,
+        now: i64,
+        cooldown: u32,
+        credit: i64,
+    ) -> (PlayerState, i64) {
+        let mut players = self.players.write().unwrap();
+        let player = players.states.entry(id).or_default();
+
+        player.total_messages += 1;
+        let credited = if now - player.last_action > cooldown as i64 {
+            player.sanitized_messages += 1;
+            player.last_action = now;
+            match player.infected {
+                true => credit,
+                false => 0,
+            }
+        } else {
+            0
+        };
+        player.cure_progress += credited;
+        let player = player.clone();
+
+        players.dirty.insert(id);
+        if players.dirty.len() >= self.batch_size {
+            self.flush.notify_one();
+        }
+
+        (player, credited)
+    }
+
+    /// Infects or cures the player. This should only be called once the change has been saved.
+    pub fn set_infected(&self, id: u64, infected: bool, now: i64) {
+        let mut players = self.players.write().unwrap();
+        let player = players.states.entry(id).or_default();
+        *player = player.with_infected(infected, now);
+    }
+
+    /// Starts the player's cooldown on infecting others through `vector`
+    pub fn record_transmission(&self, id: u64, vector: TransmissionVector, now: i64) {
+        self.players
+            .write()
+            .unwrap()
+            .states
+            .entry(id)
+            .or_default()
+            .last_transmissions
+            .insert(vector, now);
+    }
+
+    /// Counts a treatment as `credit` messages towards curing the player. Returns their new
+    /// state. The doctor's cooldown is checked by [`treatments::check`].
+    ///
+    /// [`treatments::check`]: crate::treatments::check
+    pub fn record_treatment(&self, id: u64, credit: i64) -> PlayerState {
+        let mut players = self.players.write().unwrap();
+        let player = players.states.entry(id).or_default();
+        player.cure_progress += credit;
+        let player = player.clone();
+        players.dirty.insert(id);
+
+        player
+    }
+
+    /// Sets the player's latest vaccine. This should only be called once it has been saved.
+    pub fn set_vaccine(&self, id: u64, vaccine: Vaccine) {
+        let mut players = self.players.write().unwrap();
+        players.states.entry(id).or_default().vaccine = Some(vaccine);
+    }
+
+    /// Forgets the player, e.g. when their data is deleted
+    pub fn remove(&self, id: u64) {
+        let mut players = self.players.write().unwrap();
+        players.states.remove(&id);
+        players.dirty.remove(&id);
+    }
+
+    /// Writes the counters of every dirty player in a single transaction
+    pub async fn flush(&self, pool: &SqlitePool) -> Result<()> {
+        let batch: Vec<_> = {
+            let mut players = self.players.write().unwrap();
+            let dirty = std::mem::take(&mut players.dirty);
+            dirty
+                .into_iter()
+                .filter_map(|id| Some((id, players.states.get(&id)?.clone())))
+                .collect()
+        };
+
+        if batch.is_empty() {
+            return Ok(());
+        }
+
+        if let Err(e) = Self::write_counters(pool, &batch).await {
+            // put them back so the next flush tries again
+            self.players
+                .write()
+                .unwrap()
+                .dirty
+                .extend(batch.iter().map(|(id, _)| id));
+            return Err(e);
+        }
+
+        trace!("flushed counters for {} players", batch.len());
+
+        Ok(())
+    }
+
+    /// Only the counters are written - infection state is always saved as soon as it changes,
+    /// and this batch may be older than that.
+    async fn write_counters(pool: &SqlitePool, batch: &[(u64, PlayerState)]) -> Result<()> {
+        let mut tx = pool.begin().await?;
+
+        for (id, state) in batch {
+            let id = id.to_string();
+            sqlx::query!(
+                r#"
+                INSERT INTO players
+                    (id, total_messages, sanitized_messages, last_action, cure_progress)
+                VALUES (?, ?, ?, ?, ?)
+                ON CONFLICT (id) DO UPDATE SET
+                    total_messages = excluded.total_messages,
+                    sanitized_messages = excluded.sanitized_messages,
+                    last_action = excluded.last_action,
+                    cure_progress = excluded.cure_progress
+                "#,
+                id,
+                state.total_messages,
+                state.sanitized_messages,
+                state.last_action,
+                state.cure_progress,
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Spawns the task that flushes dirty counters every `interval`, or sooner if enough players
+    /// are dirty
+    pub fn spawn_flusher(self: &Arc<Self>, pool: SqlitePool, interval: Duration) {
+        let store = self.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = store.flush.notified() => (),
+                    _ = tokio::time::sleep(interval) => (),
+                }
+
+                if let Err(e) = store.flush(&pool).await {
+                    error!("Failed to flush player counters: {:?}", e);
+                }
+            }
+        });
+    }
+}
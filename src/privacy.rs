@@ -0,0 +1,349 @@
+//! Exporting and deleting everything stored about a player, for `/mydata`.
+//!
+//! Deleting a player doesn't delete their infection records, since other players' infections
+//! point at them. Instead they're moved to a pseudonymous player, so the graph and the stats stay
+//! the same but can't be traced back to the member.
+
+use std::collections::HashMap;
+
+use color_eyre::Result;
+use serde_json::{Value, json};
+use sqlx::{Sqlite, SqlitePool, Transaction};
+
+/// Replaces the ids of other players in an export with labels like `player 1`, so an export
+/// doesn't reveal who infected or treated the player. Labels are only consistent within one export.
+struct Labels<'a> {
+    own: &'a str,
+    others: HashMap<String, String>,
+}
+
+impl<'a> Labels<'a> {
+    fn new(own: &'a str) -> Self {
+        Self {
+            own,
+            others: HashMap::new(),
+        }
+    }
+
+    fn label(&mut self, id: &str) -> String {
+        if id == self.own {
+            return id.to_string();
+        }
+
+        let next = self.others.len() + 1;
+        self.others
+            .entry(id.to_string())
+            .or_insert_with(|| format!("player {}", next))
+            .clone()
+    }
+
+    /// Labels every mention in a reason, e.g. "Infected by replying to <@id>"
+    fn reason(&mut self, reason: &str) -> String {
+        let mut out = String::with_capacity(reason.len());
+        let mut rest = reason;
+        while let Some(start) = rest.find("<@") {
+            out.push_str(&rest[..start]);
+            rest = &rest[start..];
+
+            let digits = rest[2..].find(|c: char| !c.is_ascii_digit());
+            match digits {
+                Some(n) if n > 0 && rest[2 + n..].starts_with('>') => {
+                    let id = &rest[2..2 + n];
+                    if id == self.own {
+                        out.push_str(&rest[..3 + n]);
+                    } else {
+                        out.push_str(&self.label(id));
+                    }
+                    rest = &rest[3 + n..];
+                }
+                _ => {
+                    out.push_str("<@");
+                    rest = &rest[2..];
+                }
+            }
+        }
+        out.push_str(rest);
+        out
+    }
+}
+
+/// Returns everything stored about the player as JSON. Other players are replaced with labels.
+pub async fn export(pool: &SqlitePool, id: u64) -> Result<Value> {
+    let id = id.to_string();
+    let mut labels = Labels::new(&id);
+
+    let player = sqlx::query!(
+        r#"
+        SELECT infected, total_messages, sanitized_messages, last_action, cure_progress
+        FROM players WHERE id = ?
+        "#,
+        id
+    )
+    .fetch_optional(pool)
+    .await?
+    .map(|p| {
+        json!({
+            "infected": p.infected,
+            "total_messages": p.total_messages,
+            "sanitized_messages": p.sanitized_messages,
+            "last_action": p.last_action,
+            "cure_progress": p.cure_progress,
+        })
+    });
+
+    let infection_records: Vec<_> = sqlx::query!(
+        r#"
+        SELECT id, event, target, source, reason, recorded_at, target_total_messages,
+            target_sanitized_messages, reverts, vector
+        FROM infection_records WHERE target = ?1 OR source = ?1 ORDER BY recorded_at, id
+        "#,
+        id
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|r| {
+        json!({
+            "id": r.id,
+            "event": r.event,
+            "target": labels.label(&r.target),
+            "source": r.source.map(|s| labels.label(&s)),
+            "reason": r.reason.map(|r| labels.reason(&r)),
+            "recorded_at": r.recorded_at,
+            "target_total_messages": r.target_total_messages,
+            "target_sanitized_messages": r.target_sanitized_messages,
+            "reverts": r.reverts,
+            "vector": r.vector,
+        })
+    })
+    .collect();
+
+    let role_changes: Vec<_> = sqlx::query!(
+        r#"
+        SELECT role, action, created_at, attempts, last_error, completed_at, failed_at
+        FROM role_changes WHERE member = ? ORDER BY id
+        "#,
+        id
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|r| {
+        json!({
+            "role": r.role,
+            "action": r.action,
+            "created_at": r.created_at,
+            "attempts": r.attempts,
+            "last_error": r.last_error,
+            "completed_at": r.completed_at,
+            "failed_at": r.failed_at,
+        })
+    })
+    .collect();
+
+    let notification_settings = sqlx::query!(
+        r#"
+        SELECT infected, exposed, nearing_cure, cured, dms_closed_at
+        FROM notification_settings WHERE player = ?
+        "#,
+        id
+    )
+    .fetch_optional(pool)
+    .await?
+    .map(|n| {
+        json!({
+            "infected": n.infected,
+            "exposed": n.exposed,
+            "nearing_cure": n.nearing_cure,
+            "cured": n.cured,
+            "dms_closed_at": n.dms_closed_at,
+        })
+    });
+
+    let participation = sqlx::query!(
+        "SELECT status, changed_at FROM participation WHERE player = ?",
+        id
+    )
+    .fetch_optional(pool)
+    .await?
+    .map(|p| json!({ "status": p.status, "changed_at": p.changed_at }));
+
+    let treatments: Vec<_> = sqlx::query!(
+        r#"
+        SELECT doctor, patient, treated_at, kind, succeeded
+        FROM treatments WHERE doctor = ?1 OR patient = ?1 ORDER BY treated_at, id
+        "#,
+        id
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|t| {
+        json!({
+            "doctor": labels.label(&t.doctor),
+            "patient": labels.label(&t.patient),
+            "treated_at": t.treated_at,
+            "kind": t.kind,
+            "succeeded": t.succeeded,
+        })
+    })
+    .collect();
+
+    let vaccination = sqlx::query!(
+        "SELECT vaccinated_at, immune_until, strain FROM vaccinations WHERE player = ?",
+        id
+    )
+    .fetch_optional(pool)
+    .await?
+    .map(|v| {
+        json!({
+            "vaccinated_at": v.vaccinated_at,
+            "immune_until": v.immune_until,
+            "strain": v.strain,
+        })
+    });
+
+    Ok(json!({
+        "id": id,
+        "player": player,
+        "infection_records": infection_records,
+        "role_changes": role_changes,
+        "notification_settings": notification_settings,
+        "participation": participation,
+        "treatments": treatments,
+        "vaccination": vaccination,
+    }))
+}
+
+/// Removes the player's rows, moving their infection records to a new pseudonymous player and
+/// replacing their mentions in reasons with it. Returns the pseudonym.
+///
+/// Role changes that are still pending are kept until they're applied, so the member doesn't keep
+/// the infected role, then deleted by the outbox. An opt out is kept too, so the player isn't
+/// pulled back into the game.
+pub async fn delete(tx: &mut Transaction<'_, Sqlite>, id: u64) -> Result<String> {
+    let id = id.to_string();
+    let pseudonym = format!("deleted-{:016x}", rand::random::<u64>());
+
+    // the counters are kept so aggregate stats don't change
+    sqlx::query!(
+        r#"
+        INSERT INTO players
+            (id, infected, total_messages, sanitized_messages, last_action, cure_progress)
+        SELECT ?, infected, total_messages, sanitized_messages, last_action, cure_progress
+        FROM players WHERE id = ?
+        "#,
+        pseudonym,
+        id
+    )
+    .execute(&mut **tx)
+    .await?;
+
+    sqlx::query!(
+        "UPDATE infection_records SET target = ? WHERE target = ?",
+        pseudonym,
+        id
+    )
+    .execute(&mut **tx)
+    .await?;
+    sqlx::query!(
+        "UPDATE infection_records SET source = ? WHERE source = ?",
+        pseudonym,
+        id
+    )
+    .execute(&mut **tx)
+    .await?;
+    // e.g. "Manually infected by <@id>"
+    let mention = format!("<@{}>", id);
+    sqlx::query!(
+        "UPDATE infection_records SET reason = replace(reason, ?1, ?2) WHERE instr(reason, ?1)",
+        mention,
+        pseudonym
+    )
+    .execute(&mut **tx)
+    .await?;
+    sqlx::query!(
+        "UPDATE treatments SET doctor = ? WHERE doctor = ?",
+        pseudonym,
+        id
+    )
+    .execute(&mut **tx)
+    .await?;
+    sqlx::query!(
+        "UPDATE treatments SET patient = ? WHERE patient = ?",
+        pseudonym,
+        id
+    )
+    .execute(&mut **tx)
+    .await?;
+
+    sqlx::query!(
+        r#"
+        DELETE FROM role_changes
+        WHERE member = ? AND (completed_at IS NOT NULL OR failed_at IS NOT NULL)
+        "#,
+        id
+    )
+    .execute(&mut **tx)
+    .await?;
+    sqlx::query!("UPDATE role_changes SET forget = TRUE WHERE member = ?", id)
+        .execute(&mut **tx)
+        .await?;
+    sqlx::query!("DELETE FROM notification_settings WHERE player = ?", id)
+        .execute(&mut **tx)
+        .await?;
+    sqlx::query!("DELETE FROM vaccinations WHERE player = ?", id)
+        .execute(&mut **tx)
+        .await?;
+    sqlx::query!(
+        "DELETE FROM participation WHERE player = ? AND status = 'joined'",
+        id
+    )
+    .execute(&mut **tx)
+    .await?;
+
+    // an opt out still references the player's row
+    let opted_out = sqlx::query_scalar!("SELECT player FROM participation WHERE player = ?", id)
+        .fetch_optional(&mut **tx)
+        .await?
+        .is_some();
+    if opted_out {
+        sqlx::query!(
+            r#"
+            UPDATE players SET infected = FALSE, total_messages = 0, sanitized_messages = 0,
+                last_action = 0, cure_progress = 0
+            WHERE id = ?
+            "#,
+            id
+        )
+        .execute(&mut **tx)
+        .await?;
+    } else {
+        sqlx::query!("DELETE FROM players WHERE id = ?", id)
+            .execute(&mut **tx)
+            .await?;
+    }
+
+    Ok(pseudonym)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn labels_other_players() {
+        let mut labels = Labels::new("1");
+
+        assert_eq!(labels.label("1"), "1");
+        assert_eq!(labels.label("22"), "player 1");
+        assert_eq!(labels.label("deleted-00ff"), "player 2");
+        assert_eq!(labels.label("22"), "player 1");
+
+        assert_eq!(
+            labels.reason("Infected by replying to <@22> after <@1> and <@333>"),
+            "Infected by replying to player 1 after <@1> and player 3"
+        );
+        assert_eq!(labels.reason("Sent <@ 5 <@4"), "Sent <@ 5 <@4");
+    }
+}
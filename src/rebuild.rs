@@ -0,0 +1,106 @@
+//! Rebuilds `players.infected` by replaying `infection_records`, which are the source of truth for
+//! who is infected.
+
+use std::collections::HashMap;
+
+use color_eyre::Result;
+use sqlx::SqlitePool;
+
+use crate::{
+    models::{InfectionEvent, InfectionRecord, Player},
+    outbox::{RoleAction, RoleChange},
+};
+
+/// A player whose `infected` column doesn't match their infection records
+#[derive(Clone, Debug)]
+pub struct Discrepancy {
+    pub player: String,
+    /// What `players.infected` currently says
+    pub table: bool,
+    /// What the records say it should be
+    pub records: bool,
+}
+
+/// Replays the records in order, returning whether each player that appears in them ended up
+/// infected.
+pub fn replay(records: &[InfectionRecord]) -> HashMap<&str, bool> {
+    let mut state = HashMap::new();
+    for record in records {
+        let infected = match record.event {
+            InfectionEvent::Vaccinated => continue,
+            _ => record.infects(),
+        };
+        state.insert(record.target.as_str(), infected);
+    }
+    state
+}
+
+/// Compares every player against the replayed records. If `repair` is set, the players table is
+/// updated to match the records and the matching role changes are queued in the outbox.
+pub async fn rebuild(
+    pool: &SqlitePool,
+    repair: bool,
+    infected_role: u64,
+) -> Result<Vec<Discrepancy>> {
+    let mut tx = pool.begin().await?;
+
+    let records = InfectionRecord::all(&mut *tx).await?;
+    let players = sqlx::query_as!(Player, "SELECT * FROM players")
+        .fetch_all(&mut *tx)
+        .await?;
+
+    let state = replay(&records);
+    let discrepancies: Vec<_> = players
+        .into_iter()
+        .filter_map(|p| {
+            // players without any records were never infected
+            let records = state.get(p.id.as_str()).copied().unwrap_or(false);
+            (p.infected != records).then_some(Discrepancy {
+                player: p.id,
+                table: p.infected,
+                records,
+            })
+        })
+        .collect();
+
+    if repair {
+        for d in &discrepancies {
+            sqlx::query!(
+                "UPDATE players SET infected = ? WHERE id = ?",
+                d.records,
+                d.player
+            )
+            .execute(&mut *tx)
+            .await?;
+
+            let action = match d.records {
+                true => RoleAction::Add,
+                false => RoleAction::Remove,
+            };
+            RoleChange::new(&d.player, infected_role, action)
+                .enqueue(&mut *tx)
+                .await?;
+        }
+        tx.commit().await?;
+
+        if !discrepancies.is_empty() {
+            info!(
+                "Repaired {} players from infection records",
+                discrepancies.len()
+            );
+        }
+    }
+
+    Ok(discrepancies)
+}
+
+/// Formats a discrepancy as a single line for reports
+pub fn describe(d: &Discrepancy) -> String {
+    let state = |infected| if infected { "infected" } else { "healthy" };
+    format!(
+        "{}: table says {}, records say {}",
+        d.player,
+        state(d.table),
+        state(d.records)
+    )
+}
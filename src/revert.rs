@@ -0,0 +1,189 @@
+//! Reverts infection records for `/undo` and `/rollback`. History is never deleted - each
+//! reverted record gets a compensating record that points back at it through `reverts`.
+//!
+//! Only the effective history is considered (see [`InfectionRecord::effective`]), so a record
+//! can't be reverted twice and compensating records are never reverted themselves.
+
+use std::{collections::BTreeMap, fmt};
+
+use color_eyre::Result;
+use sqlx::SqlitePool;
+
+use crate::models::{InfectionEvent, InfectionRecord};
+
+/// The records to revert for a single player
+pub struct Reversal {
+    pub target: String,
+    /// newest first, each with whether the player was infected just before it
+    pub records: Vec<(InfectionRecord, bool)>,
+}
+
+impl Reversal {
+    /// Whether the player is infected once every record has been reverted
+    pub fn infected(&self) -> bool {
+        self.records.last().is_some_and(|(_, before)| *before)
+    }
+
+    /// Reverts every record in `history` from `index` onwards
+    fn from(target: String, mut history: Vec<InfectionRecord>, index: usize) -> Self {
+        let reverted = history.split_off(index);
+
+        let mut before = history.last().is_some_and(|r| r.infects());
+        let mut records = Vec::with_capacity(reverted.len());
+        for record in reverted {
+            let after = record.infects();
+            records.push((record, before));
+            before = after;
+        }
+        records.reverse();
+
+        Self { target, records }
+    }
+}
+
+pub enum UndoError {
+    NotFound,
+    /// the record has already been reverted, or reverts another record itself
+    AlreadyReverted,
+    /// the player has newer records that would have to be reverted first
+    Superseded {
+        latest: i64,
+    },
+    /// vaccinations don't change whether anyone is infected, so there's nothing to revert
+    Vaccination,
+}
+
+impl fmt::Display for UndoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotFound => write!(f, "There is no record with that id."),
+            Self::AlreadyReverted => write!(
+                f,
+                "That record has already been reverted, or is a revert itself."
+            ),
+            Self::Superseded { latest } => write!(
+                f,
+                "The player has newer records - undo record #{} first, or use /rollback.",
+                latest
+            ),
+            Self::Vaccination => write!(f, "Vaccinations can't be undone."),
+        }
+    }
+}
+
+/// Plans reverting a single record, which must be the latest effective record for its player
+pub async fn plan_undo(pool: &SqlitePool, id: i64) -> Result<Result<Reversal, UndoError>> {
+    let records = InfectionRecord::effective(pool).await?;
+
+    let Some(record) = records.iter().find(|r| r.id == id) else {
+        let exists = sqlx::query_scalar!("SELECT id FROM infection_records WHERE id = ?", id)
+            .fetch_optional(pool)
+            .await?
+            .is_some();
+        return Ok(Err(match exists {
+            true => UndoError::AlreadyReverted,
+            false => UndoError::NotFound,
+        }));
+    };
+    if matches!(record.event, InfectionEvent::Vaccinated) {
+        return Ok(Err(UndoError::Vaccination));
+    }
+
+    let target = record.target.clone();
+    let history: Vec<_> = records
+        .into_iter()
+        .filter(|r| r.target == target && !matches!(r.event, InfectionEvent::Vaccinated))
+        .collect();
+    let latest = history.last().map_or(id, |r| r.id);
+    if latest != id {
+        return Ok(Err(UndoError::Superseded { latest }));
+    }
+
+    let index = history.len() - 1;
+    Ok(Ok(Reversal::from(target, history, index)))
+}
+
+/// Plans reverting every effective infection and cure after `since`, returning one reversal per
+/// player. Vaccinations are left as they are.
+pub async fn plan_rollback(pool: &SqlitePool, since: i64) -> Result<Vec<Reversal>> {
+    let mut histories: BTreeMap<String, Vec<InfectionRecord>> = BTreeMap::new();
+    for record in InfectionRecord::effective(pool).await? {
+        if matches!(record.event, InfectionEvent::Vaccinated) {
+            continue;
+        }
+        histories
+            .entry(record.target.clone())
+            .or_default()
+            .push(record);
+    }
+
+    Ok(histories
+        .into_iter()
+        .filter_map(|(target, history)| {
+            let index = history.partition_point(|r| r.recorded_at <= since);
+            (index < history.len()).then(|| Reversal::from(target, history, index))
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(id: i64, event: InfectionEvent, reason: &str) -> InfectionRecord {
+        InfectionRecord {
+            id,
+            event,
+            target: "a".to_string(),
+            source: None,
+            reason: Some(reason.to_string()),
+            recorded_at: id * 10,
+            target_total_messages: 0,
+            target_sanitized_messages: 0,
+            reverts: None,
+            vector: None,
+        }
+    }
+
+    /// infected, cured and infected again
+    fn history() -> Vec<InfectionRecord> {
+        vec![
+            record(1, InfectionEvent::Infected, "Manually infected by <@1>"),
+            record(2, InfectionEvent::Cured, "Manually cured by <@1>"),
+            record(3, InfectionEvent::Infected, "Manually infected by <@1>"),
+        ]
+    }
+
+    fn summary(reversal: &Reversal) -> Vec<(i64, bool)> {
+        reversal
+            .records
+            .iter()
+            .map(|(r, before)| (r.id, *before))
+            .collect()
+    }
+
+    #[test]
+    fn reverts_newest_first() {
+        let reversal = Reversal::from("a".to_string(), history(), 1);
+
+        assert_eq!(summary(&reversal), [(3, false), (2, true)]);
+        // back to how it was after the first infection
+        assert!(reversal.infected());
+    }
+
+    #[test]
+    fn reverts_whole_history() {
+        let reversal = Reversal::from("a".to_string(), history(), 0);
+
+        assert_eq!(summary(&reversal), [(3, false), (2, true), (1, false)]);
+        assert!(!reversal.infected());
+    }
+
+    #[test]
+    fn reverts_nothing() {
+        let reversal = Reversal::from("a".to_string(), history(), 3);
+
+        assert!(reversal.records.is_empty());
+        assert!(!reversal.infected());
+    }
+}
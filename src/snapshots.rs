@@ -0,0 +1,156 @@
+//! Periodic snapshots of the game state, so questions like "how many were infected at 3pm
+//! yesterday" don't require replaying every infection record.
+
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use color_eyre::Result;
+use sqlx::SqlitePool;
+
+use crate::{helpers, stats};
+
+/// Counts messages per channel between snapshots
+#[derive(Default)]
+pub struct ChannelActivity(std::sync::Mutex<HashMap<u64, i64>>);
+
+impl ChannelActivity {
+    pub fn record(&self, channel_id: u64) {
+        *self.0.lock().unwrap().entry(channel_id).or_default() += 1;
+    }
+
+    /// Returns the counts so far and resets them
+    pub fn take(&self) -> HashMap<u64, i64> {
+        std::mem::take(&mut *self.0.lock().unwrap())
+    }
+}
+
+pub struct Snapshot {
+    pub taken_at: i64,
+    pub infected: i64,
+    pub healthy: i64,
+    pub active_players: i64,
+}
+
+impl Snapshot {
+    /// Fetches every snapshot taken at or after `since`, oldest first
+    pub async fn since(pool: &SqlitePool, since: i64) -> Result<Vec<Self>> {
+        Ok(sqlx::query_as!(
+            Self,
+            "SELECT taken_at, infected, healthy, active_players FROM snapshots WHERE taken_at >= ? ORDER BY taken_at",
+            since
+        )
+        .fetch_all(pool)
+        .await?)
+    }
+
+    /// Renders snapshots as a CSV file for analysis elsewhere
+    pub fn csv(snapshots: &[Self]) -> String {
+        let mut csv = "taken_at,infected,healthy,active_players\n".to_string();
+        for s in snapshots {
+            csv.push_str(&format!(
+                "{},{},{},{}\n",
+                s.taken_at, s.infected, s.healthy, s.active_players
+            ));
+        }
+        csv
+    }
+}
+
+/// Writes a single snapshot of the current state, along with the channel activity since the
+/// last one.
+pub async fn take(pool: &SqlitePool, activity: &ChannelActivity) -> Result<()> {
+    let now = helpers::now() as i64;
+    let active_since = now - stats::DEFAULT_ACTIVE_WINDOW;
+
+    let mut tx = pool.begin().await?;
+
+    let counts = sqlx::query!(
+        r#"
+        SELECT
+            COUNT(*) FILTER (WHERE infected) AS "infected!: i64",
+            COUNT(*) FILTER (WHERE NOT infected) AS "healthy!: i64",
+            COUNT(*) FILTER (WHERE last_action >= ?) AS "active!: i64"
+        FROM players
+        "#,
+        active_since
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    let snapshot_id = sqlx::query_scalar!(
+        r#"
+        INSERT INTO snapshots (taken_at, infected, healthy, active_players) VALUES (?, ?, ?, ?)
+        RETURNING id
+        "#,
+        now,
+        counts.infected,
+        counts.healthy,
+        counts.active,
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    for (channel, messages) in activity.take() {
+        let channel = channel.to_string();
+        sqlx::query!(
+            "INSERT INTO snapshot_channels (snapshot, channel, messages) VALUES (?, ?, ?)",
+            snapshot_id,
+            channel,
+            messages
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    tx.commit().await?;
+
+    trace!(
+        "took snapshot {}: {} infected, {} healthy",
+        snapshot_id, counts.infected, counts.healthy
+    );
+
+    Ok(())
+}
+
+/// Deletes snapshots older than `retention` seconds
+pub async fn prune(pool: &SqlitePool, retention: u64) -> Result<()> {
+    let cutoff = helpers::now().saturating_sub(retention) as i64;
+    let deleted = sqlx::query!("DELETE FROM snapshots WHERE taken_at < ?", cutoff)
+        .execute(pool)
+        .await?
+        .rows_affected();
+
+    if deleted > 0 {
+        debug!("pruned {} old snapshots", deleted);
+    }
+
+    Ok(())
+}
+
+/// Spawns the task that takes a snapshot every `interval` seconds
+pub fn spawn(
+    pool: SqlitePool,
+    activity: Arc<ChannelActivity>,
+    interval: u64,
+    retention: Option<u64>,
+) {
+    tokio::spawn(async move {
+        // a zero interval would make `interval` panic
+        let mut interval = tokio::time::interval(Duration::from_secs(interval.max(1)));
+        // the first tick completes immediately, and there's nothing interesting at startup
+        interval.tick().await;
+
+        loop {
+            interval.tick().await;
+
+            if let Err(e) = take(&pool, &activity).await {
+                error!("Failed to take snapshot: {:?}", e);
+            }
+
+            if let Some(retention) = retention
+                && let Err(e) = prune(&pool, retention).await
+            {
+                error!("Failed to prune snapshots: {:?}", e);
+            }
+        }
+    });
+}
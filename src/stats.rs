@@ -0,0 +1,493 @@
+//! Epidemic metrics derived from `infection_records`.
+//!
+//! Everything in here is a plain function over a slice of records so it can be reused outside of
+//! the `/stats` command - the records just need to be ordered by `recorded_at`.
+
+use std::collections::{HashMap, HashSet};
+
+use color_eyre::Result;
+use sqlx::SqlitePool;
+
+use crate::{
+    models::{InfectionEvent, InfectionRecord},
+    vaccination::Vaccine,
+};
+
+/// How long a player can go without a counted message before they stop being "active"
+pub const DEFAULT_ACTIVE_WINDOW: i64 = 7 * 24 * 60 * 60;
+
+/// The most reproduction number windows computed (and exported) at once
+pub const MAX_R_WINDOWS: usize = 1000;
+
+/// One infection of one player, from the record that infected them to the one that cured them
+#[derive(Clone, Debug)]
+pub struct Episode {
+    pub target: String,
+    pub source: Option<String>,
+    pub started_at: i64,
+    pub ended_at: Option<i64>,
+    /// The number of players this player infected while this episode was ongoing
+    pub secondary_infections: u32,
+}
+
+/// The effective reproduction number for infections starting in `[start, end)`
+#[derive(Clone, Debug)]
+pub struct RWindow {
+    pub start: i64,
+    pub end: i64,
+    pub cases: usize,
+    /// `None` if nobody was infected in this window
+    pub r: Option<f64>,
+}
+
+#[derive(Clone, Debug)]
+pub struct EpidemicStats {
+    pub episodes: Vec<Episode>,
+    pub r_windows: Vec<RWindow>,
+    pub currently_infected: usize,
+    pub active_players: usize,
+    /// Fraction of active players that have been infected at least once
+    pub attack_rate: Option<f64>,
+    /// Mean length of finished infections (seconds)
+    pub mean_duration: Option<f64>,
+    /// (infected count, timestamp)
+    pub peak_prevalence: Option<(usize, i64)>,
+    /// Time taken for the cumulative number of infections to double most recently (seconds)
+    pub doubling_time: Option<i64>,
+    /// The number of players whose vaccine currently protects them from the game's strain
+    pub vaccinated: usize,
+    /// Fraction of active players whose vaccine currently protects them
+    pub coverage: Option<f64>,
+}
+
+impl EpidemicStats {
+    /// Loads every record that hasn't been reverted, the active players and everyone's latest
+    /// vaccine from the database and computes the stats.
+    pub async fn load(
+        pool: &SqlitePool,
+        window: i64,
+        active_window: i64,
+        strain: Option<&str>,
+        now: i64,
+    ) -> Result<Self> {
+        let records = InfectionRecord::effective(pool).await?;
+        let vaccines = Vaccine::all(pool).await?;
+
+        let active_since = now - active_window;
+        let active = sqlx::query_scalar!(
+            "SELECT id FROM players WHERE last_action >= ?",
+            active_since
+        )
+        .fetch_all(pool)
+        .await?;
+
+        let vaccinated = vaccinated(&vaccines, strain, now);
+        Ok(Self::compute(&records, &active, &vaccinated, window, now))
+    }
+
+    /// `vaccinated` is every player whose vaccine currently protects them
+    pub fn compute(
+        records: &[InfectionRecord],
+        active: &[String],
+        vaccinated: &HashSet<&str>,
+        window: i64,
+        now: i64,
+    ) -> Self {
+        let episodes = episodes(records);
+
+        Self {
+            r_windows: r_windows(&episodes, window, now),
+            currently_infected: episodes.iter().filter(|e| e.ended_at.is_none()).count(),
+            active_players: active.len(),
+            attack_rate: attack_rate(&episodes, active),
+            mean_duration: mean_duration(&episodes),
+            peak_prevalence: peak_prevalence(&episodes),
+            doubling_time: doubling_time(&episodes),
+            coverage: coverage(vaccinated, active),
+            vaccinated: vaccinated.len(),
+            episodes,
+        }
+    }
+
+    /// Renders the episodes as a CSV file for analysis elsewhere
+    pub fn episodes_csv(&self) -> String {
+        let mut csv = "target,source,started_at,ended_at,secondary_infections\n".to_string();
+        for e in &self.episodes {
+            csv.push_str(&format!(
+                "{},{},{},{},{}\n",
+                e.target,
+                e.source.as_deref().unwrap_or(""),
+                e.started_at,
+                e.ended_at.map(|t| t.to_string()).unwrap_or_default(),
+                e.secondary_infections,
+            ));
+        }
+        csv
+    }
+
+    /// Renders the reproduction number windows as a CSV file for analysis elsewhere
+    pub fn r_windows_csv(&self) -> String {
+        let mut csv = "start,end,cases,r\n".to_string();
+        for w in &self.r_windows {
+            csv.push_str(&format!(
+                "{},{},{},{}\n",
+                w.start,
+                w.end,
+                w.cases,
+                w.r.map(|r| r.to_string()).unwrap_or_default(),
+            ));
+        }
+        csv
+    }
+}
+
+/// Replays the records into a list of infection episodes, in the order they started.
+/// Repeated infections of an already infected player and cures of a healthy one are ignored.
+pub fn episodes(records: &[InfectionRecord]) -> Vec<Episode> {
+    let mut episodes: Vec<Episode> = Vec::new();
+    // target -> index of their ongoing episode
+    let mut ongoing: HashMap<&str, usize> = HashMap::new();
+
+    for record in records {
+        match record.event {
+            InfectionEvent::Infected => {
+                if ongoing.contains_key(record.target.as_str()) {
+                    continue;
+                }
+
+                // manual infections have the moderator as their source, who is (hopefully) not
+                // infected themselves, so they don't count as secondary infections
+                if let Some(&i) = record.source.as_deref().and_then(|s| ongoing.get(s)) {
+                    episodes[i].secondary_infections += 1;
+                }
+
+                ongoing.insert(&record.target, episodes.len());
+                episodes.push(Episode {
+                    target: record.target.clone(),
+                    source: record.source.clone(),
+                    started_at: record.recorded_at,
+                    ended_at: None,
+                    secondary_infections: 0,
+                });
+            }
+            InfectionEvent::Cured => {
+                if let Some(i) = ongoing.remove(record.target.as_str()) {
+                    episodes[i].ended_at = Some(record.recorded_at);
+                }
+            }
+            InfectionEvent::Vaccinated => (),
+        }
+    }
+
+    episodes
+}
+
+/// Estimates R over windows of `window` seconds, each starting half a window after the last.
+/// R for a window is the mean number of secondary infections caused by the players infected in
+/// it. Recent windows are underestimated since those players haven't finished spreading yet.
+/// Only the most recent [`MAX_R_WINDOWS`] windows are returned.
+pub fn r_windows(episodes: &[Episode], window: i64, now: i64) -> Vec<RWindow> {
+    let Some(first) = episodes.first() else {
+        return Vec::new();
+    };
+
+    let step = (window / 2).max(1);
+    let mut start = first.started_at;
+    let count = (now - start).max(0) / step + 1;
+    if count > MAX_R_WINDOWS as i64 {
+        start += (count - MAX_R_WINDOWS as i64) * step;
+    }
+
+    // episodes are ordered by when they started, so the ones in each window are a contiguous
+    // range that only ever moves forwards
+    let mut windows = Vec::new();
+    let (mut lo, mut hi) = (0, 0);
+    let mut secondary = 0;
+    while start <= now {
+        let end = start + window;
+        while hi < episodes.len() && episodes[hi].started_at < end {
+            secondary += episodes[hi].secondary_infections;
+            hi += 1;
+        }
+        while lo < hi && episodes[lo].started_at < start {
+            secondary -= episodes[lo].secondary_infections;
+            lo += 1;
+        }
+
+        let cases = hi - lo;
+        windows.push(RWindow {
+            start,
+            end,
+            cases,
+            r: (cases > 0).then(|| secondary as f64 / cases as f64),
+        });
+        start += step;
+    }
+
+    windows
+}
+
+pub fn mean_duration(episodes: &[Episode]) -> Option<f64> {
+    let durations: Vec<_> = episodes
+        .iter()
+        .filter_map(|e| Some(e.ended_at? - e.started_at))
+        .collect();
+
+    match durations.len() {
+        0 => None,
+        n => Some(durations.iter().sum::<i64>() as f64 / n as f64),
+    }
+}
+
+pub fn attack_rate(episodes: &[Episode], active: &[String]) -> Option<f64> {
+    if active.is_empty() {
+        return None;
+    }
+
+    let infected: HashSet<_> = episodes.iter().map(|e| e.target.as_str()).collect();
+    let attacked = active
+        .iter()
+        .filter(|p| infected.contains(p.as_str()))
+        .count();
+
+    Some(attacked as f64 / active.len() as f64)
+}
+
+/// Every player whose latest vaccine protects them from `strain` at `now`
+pub fn vaccinated<'a>(
+    vaccines: &'a [(String, Vaccine)],
+    strain: Option<&str>,
+    now: i64,
+) -> HashSet<&'a str> {
+    vaccines
+        .iter()
+        .filter(|(_, v)| v.protects(strain, now))
+        .map(|(player, _)| player.as_str())
+        .collect()
+}
+
+pub fn coverage(vaccinated: &HashSet<&str>, active: &[String]) -> Option<f64> {
+    if active.is_empty() {
+        return None;
+    }
+
+    let covered = active
+        .iter()
+        .filter(|p| vaccinated.contains(p.as_str()))
+        .count();
+
+    Some(covered as f64 / active.len() as f64)
+}
+
+/// The highest number of players infected at once, and when that was first reached
+pub fn peak_prevalence(episodes: &[Episode]) -> Option<(usize, i64)> {
+    // (timestamp, order, change) - cures sort before infections at the same timestamp, except
+    // for the cure of an infection that started at that same timestamp
+    let mut changes: Vec<(i64, u8, i64)> = episodes
+        .iter()
+        .flat_map(|e| {
+            let cure = e
+                .ended_at
+                .map(|t| (t, if t == e.started_at { 2 } else { 0 }, -1));
+            std::iter::once((e.started_at, 1, 1)).chain(cure)
+        })
+        .collect();
+    changes.sort();
+
+    let mut current: i64 = 0;
+    let mut peak = None;
+    for (t, _, change) in changes {
+        current += change;
+        if peak.is_none_or(|(p, _)| current > p) {
+            peak = Some((current, t));
+        }
+    }
+
+    peak.map(|(p, t)| (p as usize, t))
+}
+
+/// How long it took for the cumulative number of infections to go from half its current value
+/// to its current value
+pub fn doubling_time(episodes: &[Episode]) -> Option<i64> {
+    let n = episodes.len();
+    if n < 2 {
+        return None;
+    }
+
+    let half = episodes[n / 2 - 1].started_at;
+    let last = episodes[n - 1].started_at;
+    Some(last - half)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(
+        event: InfectionEvent,
+        target: &str,
+        source: Option<&str>,
+        reason: &str,
+        at: i64,
+    ) -> InfectionRecord {
+        InfectionRecord {
+            id: 0,
+            event,
+            target: target.to_string(),
+            source: source.map(str::to_string),
+            reason: Some(reason.to_string()),
+            recorded_at: at,
+            target_total_messages: 0,
+            target_sanitized_messages: 0,
+            reverts: None,
+            vector: None,
+        }
+    }
+
+    fn infected(target: &str, source: &str, at: i64) -> InfectionRecord {
+        record(InfectionEvent::Infected, target, Some(source), "", at)
+    }
+
+    fn cured(target: &str, at: i64) -> InfectionRecord {
+        record(InfectionEvent::Cured, target, None, "", at)
+    }
+
+    /// a is infected by a moderator and infects b and c, then b is infected again while still
+    /// infected
+    fn outbreak() -> Vec<InfectionRecord> {
+        vec![
+            infected("a", "mod", 0),
+            infected("b", "a", 10),
+            infected("c", "a", 20),
+            cured("a", 30),
+            infected("b", "c", 40),
+            cured("b", 50),
+        ]
+    }
+
+    #[test]
+    fn episodes_pair_infections_with_cures() {
+        let episodes = episodes(&outbreak());
+
+        let summary: Vec<_> = episodes
+            .iter()
+            .map(|e| {
+                (
+                    e.target.as_str(),
+                    e.started_at,
+                    e.ended_at,
+                    e.secondary_infections,
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                ("a", 0, Some(30), 2),
+                ("b", 10, Some(50), 0),
+                ("c", 20, None, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn r_windows_average_secondary_infections() {
+        let episodes = episodes(&outbreak());
+        let windows = r_windows(&episodes, 20, 40);
+
+        let summary: Vec<_> = windows.iter().map(|w| (w.start, w.cases, w.r)).collect();
+        assert_eq!(
+            summary,
+            [
+                (0, 2, Some(1.0)),
+                (10, 2, Some(0.0)),
+                (20, 1, Some(0.0)),
+                (30, 0, None),
+                (40, 0, None),
+            ]
+        );
+        assert!(windows.iter().all(|w| w.end == w.start + 20));
+    }
+
+    #[test]
+    fn r_windows_keeps_the_most_recent() {
+        let episodes = episodes(&outbreak());
+        let windows = r_windows(&episodes, 1, 10_000);
+
+        assert_eq!(windows.len(), MAX_R_WINDOWS);
+        assert_eq!(windows.last().unwrap().start, 10_000);
+        assert!(windows.iter().all(|w| w.cases == 0));
+    }
+
+    #[test]
+    fn r_windows_without_episodes() {
+        assert!(r_windows(&[], 20, 40).is_empty());
+    }
+
+    #[test]
+    fn peak_prevalence_is_first_reached() {
+        let episodes = episodes(&outbreak());
+        assert_eq!(peak_prevalence(&episodes), Some((3, 20)));
+    }
+
+    #[test]
+    fn peak_prevalence_counts_cures_first() {
+        // b is infected as a is cured, so only one is ever infected at once
+        let records = [
+            infected("a", "mod", 0),
+            cured("a", 10),
+            infected("b", "mod", 10),
+        ];
+        assert_eq!(peak_prevalence(&episodes(&records)), Some((1, 0)));
+        assert_eq!(peak_prevalence(&[]), None);
+    }
+
+    #[test]
+    fn peak_prevalence_with_instant_cures() {
+        // b is infected and cured in the same second while a is infected
+        let records = [
+            infected("a", "mod", 0),
+            infected("b", "a", 10),
+            cured("b", 10),
+            cured("a", 20),
+        ];
+        assert_eq!(peak_prevalence(&episodes(&records)), Some((2, 10)));
+
+        let records = [infected("a", "mod", 0), cured("a", 0)];
+        assert_eq!(peak_prevalence(&episodes(&records)), Some((1, 0)));
+    }
+
+    #[test]
+    fn vaccinated_only_counts_current_protection() {
+        let vaccine = |immune_until, strain: Option<&str>| Vaccine {
+            vaccinated_at: 0,
+            immune_until,
+            strain: strain.map(str::to_string),
+        };
+        let vaccines = [
+            ("a".to_string(), vaccine(None, None)),
+            ("b".to_string(), vaccine(Some(100), Some("flu"))),
+            ("c".to_string(), vaccine(Some(50), None)),
+            ("d".to_string(), vaccine(None, Some("cold"))),
+        ];
+
+        let vaccinated = vaccinated(&vaccines, Some("flu"), 60);
+        assert_eq!(vaccinated, HashSet::from(["a", "b"]));
+
+        let active = ["a".to_string(), "c".to_string()];
+        assert_eq!(coverage(&vaccinated, &active), Some(0.5));
+    }
+
+    #[test]
+    fn doubling_time_from_half_the_infections() {
+        let records = [
+            infected("a", "mod", 0),
+            infected("b", "a", 10),
+            infected("c", "a", 20),
+            infected("d", "b", 50),
+        ];
+        // 2 infections at 10, 4 at 50
+        assert_eq!(doubling_time(&episodes(&records)), Some(40));
+        assert_eq!(doubling_time(&episodes(&records[..1])), None);
+    }
+}
@@ -0,0 +1,15 @@
+//! Helpers shared by the unit tests.
+
+use sqlx::{SqlitePool, sqlite::SqlitePoolOptions};
+
+/// A fresh in-memory database with every migration applied
+pub async fn pool() -> SqlitePool {
+    // every connection to an in-memory database gets its own database
+    let pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+    sqlx::migrate!().run(&pool).await.unwrap();
+    pool
+}
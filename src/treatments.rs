@@ -0,0 +1,267 @@
+//! Doctors curing players with `/treat`, configured by `[game.treatment]`. Each doctor has a
+//! cooldown and a number of uses a day, and treating someone can infect the doctor.
+//!
+//! Cure reactions are saved here too, with their own cooldown and daily uses from
+//! `[game.reactions]`.
+
+use std::fmt;
+
+use color_eyre::Result;
+use sqlx::{Sqlite, Transaction};
+
+use crate::{config::ReactionConfig, models::Player};
+
+/// How long a doctor's uses last before they're given back (seconds)
+const DAY: i64 = 24 * 60 * 60;
+
+#[derive(serde::Deserialize, Clone)]
+pub struct TreatmentConfig {
+    /// The minimum time between one doctor's treatments (seconds, default 3600)
+    pub cooldown: Option<u32>,
+    /// The chance of a treatment curing the patient (default 0.5)
+    pub probability: Option<f64>,
+    /// How many treatments a doctor can give in 24 hours (default 3)
+    pub daily_uses: Option<u32>,
+    /// The chance of the doctor catching it from the patient (default 0.2)
+    pub exposure: Option<f64>,
+}
+
+#[derive(sqlx::Type, Clone, Copy, Debug, PartialEq, Eq)]
+#[sqlx(rename_all = "lowercase")]
+pub enum TreatmentKind {
+    /// `/treat`
+    Command,
+    /// a cure reaction
+    Reaction,
+}
+
+/// The cooldown and daily uses of one kind of treatment
+pub struct Limits {
+    /// seconds
+    pub cooldown: i64,
+    /// unlimited if `None`
+    pub daily_uses: Option<u32>,
+}
+
+impl Limits {
+    pub fn command(config: &TreatmentConfig) -> Self {
+        Self {
+            cooldown: config.cooldown.unwrap_or(60 * 60).into(),
+            daily_uses: Some(config.daily_uses.unwrap_or(3)),
+        }
+    }
+
+    pub fn reaction(config: &ReactionConfig) -> Self {
+        Self {
+            cooldown: config.cure_cooldown.unwrap_or(60).into(),
+            daily_uses: config.cure_daily_uses,
+        }
+    }
+}
+
+/// What happened when a doctor treated a patient
+pub struct Treatment {
+    pub cured: bool,
+    /// whether the doctor caught it from the patient
+    pub doctor_infected: bool,
+    /// `None` if there's no daily limit
+    pub uses_left: Option<u32>,
+}
+
+pub enum TreatError {
+    /// the doctor tried to treat themselves
+    SelfTreatment,
+    OptedOut,
+    NotInfected,
+    OnCooldown {
+        until: i64,
+    },
+    OutOfUses {
+        until: i64,
+    },
+}
+
+impl fmt::Display for TreatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::SelfTreatment => write!(f, "You can't treat yourself."),
+            Self::OptedOut => write!(f, "That player has opted out of the game."),
+            Self::NotInfected => write!(f, "That player isn't infected."),
+            Self::OnCooldown { until } => {
+                write!(
+                    f,
+                    "You need to rest - you can treat someone again <t:{}:R>.",
+                    until
+                )
+            }
+            Self::OutOfUses { until } => write!(
+                f,
+                "You've used all your treatments for today. You get one back <t:{}:R>.",
+                until
+            ),
+        }
+    }
+}
+
+/// Checks whether the doctor can give a `kind` treatment now, returning how many uses of it
+/// they'd have left after. The treatment should be saved in the same transaction, so two can't
+/// both pass the check.
+pub async fn check(
+    limits: &Limits,
+    kind: TreatmentKind,
+    doctor: u64,
+    now: i64,
+    tx: &mut Transaction<'_, Sqlite>,
+) -> Result<Result<Option<u32>, TreatError>> {
+    let doctor = doctor.to_string();
+    let since = now - DAY;
+    let recent = sqlx::query_scalar!(
+        r#"
+        SELECT treated_at FROM treatments WHERE doctor = ? AND kind = ? AND treated_at > ?
+        ORDER BY treated_at
+        "#,
+        doctor,
+        kind,
+        since
+    )
+    .fetch_all(&mut **tx)
+    .await?;
+
+    if let Some(last) = recent.last()
+        && now - last < limits.cooldown
+    {
+        return Ok(Err(TreatError::OnCooldown {
+            until: last + limits.cooldown,
+        }));
+    }
+
+    let Some(daily_uses) = limits.daily_uses else {
+        return Ok(Ok(None));
+    };
+    let daily_uses = daily_uses as usize;
+    if recent.len() >= daily_uses {
+        // the oldest use in the window is the next one given back
+        let until = recent.first().map_or(now, |t| t + DAY);
+        return Ok(Err(TreatError::OutOfUses { until }));
+    }
+
+    Ok(Ok(Some((daily_uses - recent.len() - 1) as u32)))
+}
+
+/// Saves a treatment, which starts the doctor's cooldown and uses up one of their treatments of
+/// that kind
+pub async fn save(
+    doctor: u64,
+    patient: u64,
+    now: i64,
+    kind: TreatmentKind,
+    succeeded: bool,
+    tx: &mut Transaction<'_, Sqlite>,
+) -> Result<()> {
+    let (doctor, patient) = (doctor.to_string(), patient.to_string());
+
+    Player::create_if_missing(&doctor, &mut **tx).await?;
+    Player::create_if_missing(&patient, &mut **tx).await?;
+    sqlx::query!(
+        r#"
+        INSERT INTO treatments (doctor, patient, treated_at, kind, succeeded)
+        VALUES (?, ?, ?, ?, ?)
+        "#,
+        doctor,
+        patient,
+        now,
+        kind,
+        succeeded
+    )
+    .execute(&mut **tx)
+    .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::SqlitePool;
+
+    use super::*;
+    use crate::test_support::pool;
+
+    const COMMAND: Limits = Limits {
+        cooldown: 60 * 60,
+        daily_uses: Some(3),
+    };
+
+    async fn treat(
+        pool: &SqlitePool,
+        limits: &Limits,
+        kind: TreatmentKind,
+        now: i64,
+    ) -> Result<Option<u32>, TreatError> {
+        let mut tx = pool.begin().await.unwrap();
+        let result = check(limits, kind, 1, now, &mut tx).await.unwrap();
+        if result.is_ok() {
+            save(1, 2, now, kind, true, &mut tx).await.unwrap();
+        }
+        tx.commit().await.unwrap();
+        result
+    }
+
+    #[tokio::test]
+    async fn cooldown_and_daily_uses() {
+        let pool = pool().await;
+        let kind = TreatmentKind::Command;
+
+        assert!(matches!(treat(&pool, &COMMAND, kind, 0).await, Ok(Some(2))));
+        assert!(matches!(
+            treat(&pool, &COMMAND, kind, 100).await,
+            Err(TreatError::OnCooldown { until: 3600 })
+        ));
+        assert!(matches!(
+            treat(&pool, &COMMAND, kind, 3600).await,
+            Ok(Some(1))
+        ));
+        assert!(matches!(
+            treat(&pool, &COMMAND, kind, 7200).await,
+            Ok(Some(0))
+        ));
+        // the first use is given back a day after it was used
+        assert!(matches!(
+            treat(&pool, &COMMAND, kind, 10800).await,
+            Err(TreatError::OutOfUses { until: DAY })
+        ));
+        assert!(matches!(
+            treat(&pool, &COMMAND, kind, DAY).await,
+            Ok(Some(0))
+        ));
+    }
+
+    #[tokio::test]
+    async fn kinds_are_counted_separately() {
+        let pool = pool().await;
+        let reaction = Limits {
+            cooldown: 60,
+            daily_uses: None,
+        };
+
+        assert!(matches!(
+            treat(&pool, &COMMAND, TreatmentKind::Command, 0).await,
+            Ok(Some(2))
+        ));
+        assert!(matches!(
+            treat(&pool, &reaction, TreatmentKind::Reaction, 0).await,
+            Ok(None)
+        ));
+        assert!(matches!(
+            treat(&pool, &reaction, TreatmentKind::Reaction, 30).await,
+            Err(TreatError::OnCooldown { until: 60 })
+        ));
+        assert!(matches!(
+            treat(&pool, &reaction, TreatmentKind::Reaction, 60).await,
+            Ok(None)
+        ));
+        assert!(matches!(
+            treat(&pool, &COMMAND, TreatmentKind::Command, 60).await,
+            Err(TreatError::OnCooldown { until: 3600 })
+        ));
+    }
+}
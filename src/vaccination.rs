@@ -0,0 +1,252 @@
+//! Vaccinating players with `/vaccinate`, configured by `[game.vaccination]`. Doses come from a
+//! shared pool that refills on a schedule, so outbreaks become a race between the infection and
+//! the rollout.
+//!
+//! Vaccinated players can't catch it from other players while they're immune, but moderators can
+//! still infect them.
+
+use std::fmt;
+
+use color_eyre::Result;
+use sqlx::{Sqlite, SqlitePool, Transaction};
+
+use crate::models::Player;
+
+#[derive(serde::Deserialize, Clone)]
+pub struct VaccinationConfig {
+    /// The most doses the pool can hold. It starts full
+    pub doses: u32,
+    /// How often doses are added to the pool (seconds, default 86400)
+    pub refill_interval: Option<u32>,
+    /// How many doses are added each time (default: enough to fill the pool)
+    pub refill_amount: Option<u32>,
+    /// How long a vaccine protects for (seconds). Vaccines never wear off if unset
+    pub immunity: Option<u32>,
+    /// The strain the vaccine protects against, compared to `game.strain`. Protects against every
+    /// strain if unset
+    pub strain: Option<String>,
+}
+
+/// A player's latest vaccine
+#[derive(Clone, Debug)]
+pub struct Vaccine {
+    pub vaccinated_at: i64,
+    /// `None` if it never wears off
+    pub immune_until: Option<i64>,
+    /// `None` if it protects against every strain
+    pub strain: Option<String>,
+}
+
+impl Vaccine {
+    /// Whether the vaccine protects against `strain` at `now`
+    pub fn protects(&self, strain: Option<&str>, now: i64) -> bool {
+        self.immune_until.is_none_or(|t| now < t)
+            && (self.strain.is_none() || self.strain.as_deref() == strain)
+    }
+
+    /// Fetches every player's latest vaccine
+    pub async fn all(pool: &SqlitePool) -> Result<Vec<(String, Self)>> {
+        Ok(
+            sqlx::query!("SELECT player, vaccinated_at, immune_until, strain FROM vaccinations")
+                .fetch_all(pool)
+                .await?
+                .into_iter()
+                .map(|v| {
+                    let vaccine = Self {
+                        vaccinated_at: v.vaccinated_at,
+                        immune_until: v.immune_until,
+                        strain: v.strain,
+                    };
+                    (v.player, vaccine)
+                })
+                .collect(),
+        )
+    }
+
+    /// Saves the vaccine as the player's latest
+    pub async fn save(&self, player: &str, tx: &mut Transaction<'_, Sqlite>) -> Result<()> {
+        Player::create_if_missing(player, &mut **tx).await?;
+        sqlx::query!(
+            r#"
+            INSERT INTO vaccinations (player, vaccinated_at, immune_until, strain)
+            VALUES (?, ?, ?, ?)
+            ON CONFLICT (player) DO UPDATE SET
+                vaccinated_at = excluded.vaccinated_at,
+                immune_until = excluded.immune_until,
+                strain = excluded.strain
+            "#,
+            player,
+            self.vaccinated_at,
+            self.immune_until,
+            self.strain,
+        )
+        .execute(&mut **tx)
+        .await?;
+        Ok(())
+    }
+}
+
+/// A successful vaccination
+pub struct Vaccination {
+    pub immune_until: Option<i64>,
+    /// doses left in the pool
+    pub doses_left: u32,
+}
+
+pub enum VaccinateError {
+    OptedOut,
+    /// vaccines don't help players who are already infected
+    Infected,
+    AlreadyImmune {
+        until: Option<i64>,
+    },
+    OutOfDoses {
+        /// when the next doses are added
+        refill_at: i64,
+    },
+}
+
+impl fmt::Display for VaccinateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::OptedOut => write!(f, "That player has opted out of the game."),
+            Self::Infected => write!(f, "That player is already infected."),
+            Self::AlreadyImmune { until: Some(until) } => {
+                write!(f, "That player is immune until <t:{}:f>.", until)
+            }
+            Self::AlreadyImmune { until: None } => write!(f, "That player is already immune."),
+            Self::OutOfDoses { refill_at } => write!(
+                f,
+                "There are no doses left. More arrive <t:{}:R>.",
+                refill_at
+            ),
+        }
+    }
+}
+
+/// Takes a dose from the pool, first adding the doses for every refill since the last one.
+/// Returns the number of doses left, or when the next doses arrive if the pool is empty.
+pub async fn take_dose(
+    config: &VaccinationConfig,
+    now: i64,
+    tx: &mut Transaction<'_, Sqlite>,
+) -> Result<Result<u32, i64>> {
+    let interval = config.refill_interval.unwrap_or(24 * 60 * 60).max(1) as i64;
+    let amount = config.refill_amount.unwrap_or(config.doses) as i64;
+    let doses = config.doses as i64;
+
+    let (available, refilled_at) =
+        sqlx::query!("SELECT available, refilled_at FROM vaccine_doses WHERE id = 1")
+            .fetch_optional(&mut **tx)
+            .await?
+            .map_or((doses, now), |p| (p.available, p.refilled_at));
+
+    // refills that were due while nobody was vaccinated are all added at once
+    let refills = (now - refilled_at) / interval;
+    let available = (available + refills * amount).min(doses);
+    let refilled_at = refilled_at + refills * interval;
+
+    if available <= 0 {
+        return Ok(Err(refilled_at + interval));
+    }
+
+    let available = available - 1;
+    sqlx::query!(
+        r#"
+        INSERT INTO vaccine_doses (id, available, refilled_at) VALUES (1, ?, ?)
+        ON CONFLICT (id) DO UPDATE SET
+            available = excluded.available,
+            refilled_at = excluded.refilled_at
+        "#,
+        available,
+        refilled_at
+    )
+    .execute(&mut **tx)
+    .await?;
+
+    Ok(Ok(available as u32))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::pool;
+
+    async fn take(pool: &SqlitePool, config: &VaccinationConfig, now: i64) -> Result<u32, i64> {
+        let mut tx = pool.begin().await.unwrap();
+        let result = take_dose(config, now, &mut tx).await.unwrap();
+        tx.commit().await.unwrap();
+        result
+    }
+
+    #[tokio::test]
+    async fn doses_refill() {
+        let pool = pool().await;
+        let config = VaccinationConfig {
+            doses: 3,
+            refill_interval: Some(100),
+            refill_amount: Some(1),
+            immunity: None,
+            strain: None,
+        };
+
+        // the pool starts full
+        assert_eq!(take(&pool, &config, 0).await, Ok(2));
+        assert_eq!(take(&pool, &config, 10).await, Ok(1));
+        assert_eq!(take(&pool, &config, 20).await, Ok(0));
+        assert_eq!(take(&pool, &config, 30).await, Err(100));
+        // two refills were due, and the next is due at 300
+        assert_eq!(take(&pool, &config, 250).await, Ok(1));
+        assert_eq!(take(&pool, &config, 260).await, Ok(0));
+        assert_eq!(take(&pool, &config, 270).await, Err(300));
+        // never more than a full pool
+        assert_eq!(take(&pool, &config, 10_000).await, Ok(2));
+    }
+
+    #[tokio::test]
+    async fn refills_fill_the_pool_by_default() {
+        let pool = pool().await;
+        let config = VaccinationConfig {
+            doses: 2,
+            refill_interval: None,
+            refill_amount: None,
+            immunity: None,
+            strain: None,
+        };
+
+        assert_eq!(take(&pool, &config, 0).await, Ok(1));
+        assert_eq!(take(&pool, &config, 1).await, Ok(0));
+        assert_eq!(take(&pool, &config, 2).await, Err(24 * 60 * 60));
+        assert_eq!(take(&pool, &config, 24 * 60 * 60).await, Ok(1));
+    }
+
+    #[test]
+    fn vaccines_wear_off() {
+        let vaccine = Vaccine {
+            vaccinated_at: 0,
+            immune_until: Some(100),
+            strain: None,
+        };
+        assert!(vaccine.protects(None, 99));
+        assert!(vaccine.protects(Some("flu"), 99));
+        assert!(!vaccine.protects(None, 100));
+
+        let forever = Vaccine {
+            immune_until: None,
+            ..vaccine
+        };
+        assert!(forever.protects(None, i64::MAX));
+    }
+
+    #[test]
+    fn vaccines_protect_against_their_strain() {
+        let vaccine = Vaccine {
+            vaccinated_at: 0,
+            immune_until: None,
+            strain: Some("flu".to_string()),
+        };
+        assert!(vaccine.protects(Some("flu"), 0));
+        assert!(!vaccine.protects(Some("cold"), 0));
+        assert!(!vaccine.protects(None, 0));
+    }
+}
@@ -0,0 +1,190 @@
+//! Voice channels as rooms, configured by `[game.voice]`. Members who spend long enough in the
+//! same voice channel as an infected member can catch it. Occupancy is tracked from voice state
+//! updates, and the game checks it on every tick.
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Mutex,
+};
+
+use crate::{channels::ChannelMultipliers, config::VectorConfig};
+
+#[derive(serde::Deserialize, Clone)]
+pub struct VoiceConfig {
+    /// The chance of catching it once exposed, and the cooldown on infected members spreading it
+    #[serde(flatten)]
+    pub transmission: VectorConfig,
+    /// How long a member has to spend with an infected member before they're exposed (seconds,
+    /// default 300). Time adds up across calls until they're exposed
+    pub exposure: Option<u32>,
+    /// How often occupancy is checked (seconds, default 60)
+    pub interval: Option<u32>,
+}
+
+/// The members in a voice channel
+#[derive(Clone, Debug)]
+pub struct Room {
+    pub channel: u64,
+    pub multipliers: ChannelMultipliers,
+    pub members: Vec<u64>,
+}
+
+#[derive(Default)]
+struct Rooms {
+    /// which channel each member is in
+    members: HashMap<u64, u64>,
+    channels: HashMap<u64, (ChannelMultipliers, HashSet<u64>)>,
+    /// seconds each member has spent with an infected member since they were last exposed
+    exposure: HashMap<u64, i64>,
+}
+
+/// Who's in each voice channel
+#[derive(Default)]
+pub struct Occupancy(Mutex<Rooms>);
+
+impl Occupancy {
+    /// Moves the member into the channel, out of any other they were in
+    pub fn join(&self, member: u64, channel: u64, multipliers: ChannelMultipliers) {
+        let mut rooms = self.0.lock().unwrap();
+        if let Some(old) = rooms.members.insert(member, channel)
+            && old != channel
+        {
+            remove(&mut rooms, old, member);
+        }
+
+        let room = rooms
+            .channels
+            .entry(channel)
+            .or_insert_with(|| (multipliers, HashSet::new()));
+        room.0 = multipliers;
+        room.1.insert(member);
+    }
+
+    /// Removes the member from the channel they're in, if any. Their exposure is kept in case they
+    /// come back
+    pub fn leave(&self, member: u64) {
+        let mut rooms = self.0.lock().unwrap();
+        if let Some(channel) = rooms.members.remove(&member) {
+            remove(&mut rooms, channel, member);
+        }
+    }
+
+    /// Forgets everything about the member, e.g. when their data is deleted
+    pub fn forget(&self, member: u64) {
+        self.leave(member);
+        self.0.lock().unwrap().exposure.remove(&member);
+    }
+
+    /// Every channel with more than one member
+    pub fn rooms(&self) -> Vec<Room> {
+        self.0
+            .lock()
+            .unwrap()
+            .channels
+            .iter()
+            .filter(|(_, (_, members))| members.len() > 1)
+            .map(|(&channel, (multipliers, members))| Room {
+                channel,
+                multipliers: *multipliers,
+                members: members.iter().copied().collect(),
+            })
+            .collect()
+    }
+
+    /// Adds to the time the member has spent with an infected member, returning the total
+    pub fn expose(&self, member: u64, seconds: i64) -> i64 {
+        let mut rooms = self.0.lock().unwrap();
+        let exposure = rooms.exposure.entry(member).or_default();
+        *exposure += seconds;
+        *exposure
+    }
+
+    /// Resets the time the member has spent with an infected member
+    pub fn reset_exposure(&self, member: u64) {
+        self.0.lock().unwrap().exposure.remove(&member);
+    }
+}
+
+fn remove(rooms: &mut Rooms, channel: u64, member: u64) {
+    if let Some((_, members)) = rooms.channels.get_mut(&channel) {
+        members.remove(&member);
+        if members.is_empty() {
+            rooms.channels.remove(&channel);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rooms(occupancy: &Occupancy) -> Vec<(u64, Vec<u64>)> {
+        let mut rooms: Vec<_> = occupancy
+            .rooms()
+            .into_iter()
+            .map(|mut r| {
+                r.members.sort();
+                (r.channel, r.members)
+            })
+            .collect();
+        rooms.sort();
+        rooms
+    }
+
+    #[test]
+    fn only_rooms_with_company() {
+        let occupancy = Occupancy::default();
+        let m = ChannelMultipliers::default();
+        occupancy.join(1, 10, m);
+        occupancy.join(2, 10, m);
+        occupancy.join(3, 20, m);
+
+        assert_eq!(rooms(&occupancy), [(10, vec![1, 2])]);
+    }
+
+    #[test]
+    fn joining_moves_between_channels() {
+        let occupancy = Occupancy::default();
+        let m = ChannelMultipliers::default();
+        occupancy.join(1, 10, m);
+        occupancy.join(2, 10, m);
+        occupancy.join(3, 20, m);
+        occupancy.join(1, 20, m);
+
+        assert_eq!(rooms(&occupancy), [(20, vec![1, 3])]);
+
+        occupancy.leave(3);
+        occupancy.leave(3);
+        assert!(rooms(&occupancy).is_empty());
+    }
+
+    #[test]
+    fn rooms_have_the_latest_multipliers() {
+        let occupancy = Occupancy::default();
+        occupancy.join(1, 10, ChannelMultipliers::default());
+        let boosted = ChannelMultipliers {
+            transmission: 2.0,
+            cure: 1.0,
+        };
+        occupancy.join(2, 10, boosted);
+
+        assert_eq!(occupancy.rooms()[0].multipliers.transmission, 2.0);
+    }
+
+    #[test]
+    fn exposure_adds_up_until_reset() {
+        let occupancy = Occupancy::default();
+        occupancy.join(1, 10, ChannelMultipliers::default());
+
+        assert_eq!(occupancy.expose(1, 60), 60);
+        // kept across calls
+        occupancy.leave(1);
+        assert_eq!(occupancy.expose(1, 60), 120);
+
+        occupancy.reset_exposure(1);
+        assert_eq!(occupancy.expose(1, 60), 60);
+
+        occupancy.forget(1);
+        assert_eq!(occupancy.expose(1, 60), 60);
+    }
+}
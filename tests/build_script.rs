@@ -0,0 +1,4 @@
+//! build.rs can't have tests of its own, so the code it uses is tested from here
+
+#[path = "../build/command_functions.rs"]
+mod command_functions;